@@ -0,0 +1,59 @@
+use jsonrpc_core::{Id, MethodCall, Output, Params, Result, Version};
+
+use serde::de::DeserializeOwned;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+
+// Minimal blocking client for the line-delimited JSON-RPC TCP transport, used by the CLI
+// subcommands
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    next_id: u64,
+}
+
+impl Client {
+    pub fn connect(addr: &str) -> std::io::Result<Client> {
+        let writer = TcpStream::connect(addr)?;
+        let reader = BufReader::new(writer.try_clone()?);
+
+        Ok(Client {
+            reader,
+            writer,
+            next_id: 0,
+        })
+    }
+
+    pub fn call<T: DeserializeOwned>(
+        &mut self,
+        method: &str,
+        params: Vec<serde_json::Value>,
+    ) -> Result<T> {
+        self.next_id += 1;
+
+        let request = MethodCall {
+            jsonrpc: Some(Version::V2),
+            method: method.into(),
+            params: Params::Array(params),
+            id: Id::Num(self.next_id),
+        };
+
+        let mut line = serde_json::to_string(&request).unwrap();
+        line.push('\n');
+        self.writer
+            .write_all(line.as_bytes())
+            .expect("could not send request to coordinator");
+
+        let mut response = String::new();
+        self.reader
+            .read_line(&mut response)
+            .expect("could not read response from coordinator");
+
+        let output: Output =
+            serde_json::from_str(&response).expect("could not parse response from coordinator");
+        let result: Result<serde_json::Value> = output.into();
+
+        Ok(serde_json::from_value(result?).expect("unexpected result type from coordinator"))
+    }
+}
@@ -0,0 +1,81 @@
+use jsonrpc_core::futures::future::{self, Either};
+use jsonrpc_core::futures::Future;
+use jsonrpc_core::{Call, Middleware, Output};
+
+use crate::error::Code;
+use crate::Meta;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+pub type CallFuture = Box<dyn Future<Item = Option<Output>, Error = ()> + Send>;
+
+// Counts calls being handled across every transport sharing in_flight, and answers "server busy"
+// once max_concurrent are running instead of letting the backlog grow. With no maximum, calls are
+// only counted.
+pub struct ConcurrencyLimit {
+    in_flight: Arc<AtomicUsize>,
+    max_concurrent: Option<usize>,
+}
+
+// Held for as long as a call runs, so the slot is released however its future ends
+struct Permit(Arc<AtomicUsize>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ConcurrencyLimit {
+    pub fn new(in_flight: Arc<AtomicUsize>, max_concurrent: Option<usize>) -> ConcurrencyLimit {
+        ConcurrencyLimit {
+            in_flight,
+            max_concurrent,
+        }
+    }
+
+    fn try_acquire(&self) -> Option<Permit> {
+        let running = self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let permit = Permit(self.in_flight.clone());
+
+        match self.max_concurrent {
+            Some(max_concurrent) if running >= max_concurrent => None,
+            _ => Some(permit),
+        }
+    }
+}
+
+impl Middleware<Meta> for ConcurrencyLimit {
+    type Future = jsonrpc_core::middleware::NoopFuture;
+    type CallFuture = CallFuture;
+
+    fn on_call<F, X>(&self, call: Call, meta: Meta, next: F) -> Either<Self::CallFuture, X>
+    where
+        F: Fn(Call, Meta) -> X + Send + Sync,
+        X: Future<Item = Option<Output>, Error = ()> + Send + 'static,
+    {
+        // Answering an invalid call costs nothing, so those are never refused
+        if let Call::Invalid { .. } = call {
+            return Either::B(next(call, meta));
+        }
+
+        let permit = match self.try_acquire() {
+            Some(permit) => permit,
+            None => return Either::A(Box::new(future::ok(busy_output(call)))),
+        };
+
+        Either::A(Box::new(next(call, meta).then(move |output| {
+            drop(permit);
+            output
+        })))
+    }
+}
+
+// Notifications get no response, so refusing one just drops it
+fn busy_output(call: Call) -> Option<Output> {
+    match call {
+        Call::MethodCall(call) => Some(Output::from(Err(Code::Busy.into()), call.id, call.jsonrpc)),
+        Call::Notification(_) | Call::Invalid { .. } => None,
+    }
+}
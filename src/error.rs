@@ -0,0 +1,59 @@
+use jsonrpc_core::{Error, ErrorCode};
+
+// Application errors returned to clients. The numeric codes are part of the RPC contract, so
+// existing variants must keep theirs; new ones take an unused code in the server error range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Code {
+    QuotaExceeded,
+    UnderMaintenance,
+    NotFound,
+    Forbidden,
+    ReadOnly,
+    Conflict,
+    DuplicateName,
+    DuplicateContent,
+    RateLimited,
+    Busy,
+}
+
+impl Code {
+    pub fn code(self) -> i64 {
+        match self {
+            Code::QuotaExceeded => -32001,
+            Code::UnderMaintenance => -32003,
+            Code::NotFound => -32004,
+            Code::Forbidden => -32005,
+            Code::ReadOnly => -32006,
+            Code::Conflict => -32009,
+            Code::DuplicateName => -32010,
+            Code::DuplicateContent => -32011,
+            Code::RateLimited => -32029,
+            Code::Busy => -32030,
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            Code::QuotaExceeded => "quota exceeded",
+            Code::UnderMaintenance => "under maintenance",
+            Code::NotFound => "not found",
+            Code::Forbidden => "forbidden",
+            Code::ReadOnly => "read-only mode",
+            Code::Conflict => "conflict",
+            Code::DuplicateName => "duplicate name",
+            Code::DuplicateContent => "duplicate content",
+            Code::RateLimited => "rate limited",
+            Code::Busy => "server busy",
+        }
+    }
+}
+
+impl From<Code> for Error {
+    fn from(code: Code) -> Error {
+        Error {
+            code: ErrorCode::ServerError(code.code()),
+            message: code.message().into(),
+            data: None,
+        }
+    }
+}
@@ -1,12 +1,60 @@
-use jsonrpc_core::{Error, IoHandler, Result};
+use jsonrpc_core::{Error, MetaIoHandler, Metadata, Result};
 use jsonrpc_derive::rpc;
 
 use serde_derive::{Deserialize, Serialize};
 
 use r2d2_postgres::{PostgresConnectionManager, TlsMode};
 
+use postgres::GenericConnection;
+
 use multihash::{decode, encode, Hash};
 
+use base64::prelude::{Engine, BASE64_STANDARD};
+
+use futures::Future;
+
+use std::io::Read;
+use std::net::SocketAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const APPLICATION_NAME: &str = "violetear-coordinator";
+
+pub const DB_POOL_MAX_SIZE: u32 = 10;
+
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+const VERIFY_BATCH_SIZE: i64 = 100;
+
+const TASK_LOG_MAX_BYTES: i64 = 1024 * 1024;
+
+const TASK_LIST_MAX_LIMIT: i64 = 1000;
+
+// self_test's profile lives under this base, so it can't be mistaken for a real one
+const SELF_TEST_BASE: &str = "__self_test";
+
+const TASK_FIELDS: &[&str] = &[
+    "id",
+    "profile_id",
+    "file_name",
+    "data",
+    "multihash",
+    "size_bytes",
+    "content_type",
+    "created_at",
+];
+
+const TABLES: &[&str] = &[
+    "profiles",
+    "tasks",
+    "base_defaults",
+    "task_logs",
+    "audit_log",
+];
+
 #[derive(Serialize, Deserialize)]
 pub struct TaskId(i64);
 
@@ -19,6 +67,63 @@ pub struct Profile {
     base: String,
     name: String,
     json: String,
+    max_tasks: Option<i64>,
+    version: i32,
+    archived: bool,
+    hash_algorithm: Option<String>,
+    task_count: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ProfileWithTasks {
+    profile: Profile,
+    tasks: Vec<TaskSummary>,
+}
+
+// Operations atomic_batch can run. A task may be created for a profile created earlier in the same
+// batch by giving {"created_by": <index of that operation>} as its profile.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    CreateProfile {
+        base_name: String,
+        name: String,
+        json: String,
+    },
+    UpdateProfileJson {
+        id: ProfileId,
+        json: String,
+        expected_version: i32,
+    },
+    CreateTask {
+        profile: BatchProfile,
+        file_name: String,
+        data: Vec<u8>,
+        content_type: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum BatchProfile {
+    Id(ProfileId),
+    CreatedBy { created_by: usize },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum OperationResult {
+    CreateProfile { id: ProfileId },
+    UpdateProfileJson,
+    CreateTask { id: TaskId },
+}
+
+// import_directory answers a dry run with the number of files it would import
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ImportResult {
+    Created(Vec<TaskId>),
+    DryRun(i64),
 }
 
 #[derive(Serialize, Deserialize)]
@@ -28,212 +133,2990 @@ pub struct Task {
     file_name: String,
     data: Vec<u8>,
     multihash: Vec<u8>,
+    size_bytes: i64,
+    content_type: String,
+}
+
+// Task with data and multihash as standard base64 strings, for clients that handle JSON number
+// arrays poorly
+#[derive(Serialize, Deserialize)]
+pub struct TaskB64 {
+    id: TaskId,
+    profile_id: ProfileId,
+    file_name: String,
+    data: String,
+    multihash: String,
+    size_bytes: i64,
+    content_type: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TaskSummary {
+    id: TaskId,
+    profile_id: ProfileId,
+    file_name: String,
+    size_bytes: i64,
+    content_type: String,
+    created_at: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ListPage<T> {
+    items: Vec<T>,
+    total: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TaskLogLine {
+    seq: i64,
+    at: i64,
+    line: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuditEntry {
+    id: i64,
+    at: i64,
+    method: String,
+    args: String,
+    caller: String,
+    outcome: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DedupStats {
+    total_tasks: i64,
+    distinct_multihashes: i64,
+    total_bytes: i64,
+    distinct_bytes: i64,
+    bytes_saved: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PoolStatus {
+    connections: u32,
+    idle_connections: u32,
+    max_size: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RequestStatus {
+    in_flight: usize,
+    max_concurrent: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VersionInfo {
+    version: String,
+    maintenance_mode: bool,
+    read_only: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SelfTestStep {
+    name: String,
+    elapsed_ms: u64,
+    error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SelfTestReport {
+    ok: bool,
+    steps: Vec<SelfTestStep>,
 }
 
+#[derive(Clone)]
+pub struct Meta {
+    peer_addr: SocketAddr,
+}
+
+impl Metadata for Meta {}
+
 #[rpc]
 pub trait Rpc {
-    #[rpc(name = "create_profile")]
-    fn create_profile(&self, base_name: String, name: String, json: String) -> Result<ProfileId>;
+    type Metadata;
+
+    #[rpc(meta, name = "create_profile")]
+    fn create_profile(
+        &self,
+        meta: Self::Metadata,
+        base_name: String,
+        name: String,
+        json: String,
+    ) -> Result<ProfileId>;
+
+    #[rpc(meta, name = "get_or_create_profile")]
+    fn get_or_create_profile(
+        &self,
+        meta: Self::Metadata,
+        base_name: String,
+        name: String,
+        json: String,
+    ) -> Result<(ProfileId, bool)>;
+
+    #[rpc(meta, name = "set_base_defaults")]
+    fn set_base_defaults(&self, meta: Self::Metadata, base: String, json: String) -> Result<()>;
 
     #[rpc(name = "list_profiles")]
-    fn list_profiles(&self, by_base: Option<String>) -> Result<Vec<ProfileId>>;
+    fn list_profiles(
+        &self,
+        by_base: Option<String>,
+        include_archived: Option<bool>,
+    ) -> Result<Vec<ProfileId>>;
+
+    #[rpc(name = "list_profiles_by_bases")]
+    fn list_profiles_by_bases(
+        &self,
+        bases: Vec<String>,
+        include_archived: Option<bool>,
+    ) -> Result<Vec<ProfileId>>;
+
+    #[rpc(name = "profile_exists")]
+    fn profile_exists(&self, id: ProfileId) -> Result<bool>;
 
     #[rpc(name = "fetch_profile")]
-    fn fetch_profile(&self, id: ProfileId) -> Result<Profile>;
+    fn fetch_profile(&self, id: ProfileId, with_task_count: Option<bool>) -> Result<Profile>;
+
+    #[rpc(name = "fetch_profile_with_tasks")]
+    fn fetch_profile_with_tasks(&self, id: ProfileId, task_limit: i64) -> Result<ProfileWithTasks>;
+
+    #[rpc(name = "fetch_profile_json")]
+    fn fetch_profile_json(&self, id: ProfileId) -> Result<String>;
+
+    #[rpc(name = "fetch_profile_by_name")]
+    fn fetch_profile_by_name(&self, name: String) -> Result<Profile>;
+
+    #[rpc(meta, name = "set_profile_max_tasks")]
+    fn set_profile_max_tasks(
+        &self,
+        meta: Self::Metadata,
+        id: ProfileId,
+        max_tasks: Option<i64>,
+    ) -> Result<()>;
 
-    #[rpc(name = "create_task")]
-    fn create_task(&self, profile: ProfileId, file_name: String, data: Vec<u8>) -> Result<TaskId>;
+    #[rpc(meta, name = "set_profile_hash_algorithm")]
+    fn set_profile_hash_algorithm(
+        &self,
+        meta: Self::Metadata,
+        id: ProfileId,
+        hash_algorithm: Option<String>,
+    ) -> Result<()>;
+
+    #[rpc(meta, name = "update_profile_json")]
+    fn update_profile_json(
+        &self,
+        meta: Self::Metadata,
+        id: ProfileId,
+        json: String,
+        expected_version: i32,
+    ) -> Result<()>;
+
+    #[rpc(meta, name = "rename_profile")]
+    fn rename_profile(
+        &self,
+        meta: Self::Metadata,
+        id: ProfileId,
+        name: String,
+        expected_version: i32,
+    ) -> Result<()>;
+
+    #[rpc(meta, name = "archive_profile")]
+    fn archive_profile(&self, meta: Self::Metadata, id: ProfileId) -> Result<()>;
+
+    #[rpc(meta, name = "unarchive_profile")]
+    fn unarchive_profile(&self, meta: Self::Metadata, id: ProfileId) -> Result<()>;
+
+    #[rpc(meta, name = "touch_profile")]
+    fn touch_profile(&self, meta: Self::Metadata, id: ProfileId) -> Result<()>;
+
+    #[rpc(name = "diff_profiles")]
+    fn diff_profiles(&self, a: ProfileId, b: ProfileId) -> Result<String>;
+
+    #[rpc(meta, name = "create_task")]
+    fn create_task(
+        &self,
+        meta: Self::Metadata,
+        profile: ProfileId,
+        file_name: String,
+        data: Vec<u8>,
+        content_type: Option<String>,
+    ) -> Result<TaskId>;
+
+    #[rpc(meta, name = "import_directory")]
+    fn import_directory(
+        &self,
+        meta: Self::Metadata,
+        profile: ProfileId,
+        base_path: String,
+        dry_run: Option<bool>,
+    ) -> Result<ImportResult>;
+
+    #[rpc(meta, name = "update_task_data")]
+    fn update_task_data(
+        &self,
+        meta: Self::Metadata,
+        id: TaskId,
+        data: Vec<u8>,
+        content_type: Option<String>,
+    ) -> Result<()>;
+
+    #[rpc(meta, name = "rename_tasks_by_pattern")]
+    fn rename_tasks_by_pattern(
+        &self,
+        meta: Self::Metadata,
+        profile: ProfileId,
+        from: String,
+        to: String,
+        dry_run: Option<bool>,
+    ) -> Result<i64>;
+
+    #[rpc(meta, name = "atomic_batch")]
+    fn atomic_batch(
+        &self,
+        meta: Self::Metadata,
+        ops: Vec<Operation>,
+    ) -> Result<Vec<OperationResult>>;
 
     #[rpc(name = "list_tasks")]
     fn list_tasks(&self, by_profile: Option<ProfileId>) -> Result<Vec<TaskId>>;
 
+    #[rpc(name = "list_profile_tasks")]
+    fn list_profile_tasks(
+        &self,
+        profile: ProfileId,
+        limit: i64,
+        offset: i64,
+    ) -> Result<ListPage<TaskSummary>>;
+
+    #[rpc(name = "list_recent_tasks")]
+    fn list_recent_tasks(&self, limit: i64) -> Result<Vec<TaskSummary>>;
+
+    #[rpc(name = "tasks_since")]
+    fn tasks_since(
+        &self,
+        after_id: TaskId,
+        limit: i64,
+        include_data: Option<bool>,
+    ) -> Result<Vec<Task>>;
+
+    #[rpc(name = "fetch_task_range")]
+    fn fetch_task_range(
+        &self,
+        from_id: TaskId,
+        to_id: TaskId,
+        include_data: bool,
+    ) -> Result<Vec<Task>>;
+
+    #[rpc(name = "task_exists")]
+    fn task_exists(&self, id: TaskId) -> Result<bool>;
+
     #[rpc(name = "fetch_task")]
     fn fetch_task(&self, id: TaskId) -> Result<Task>;
+
+    #[rpc(name = "fetch_task_b64")]
+    fn fetch_task_b64(&self, id: TaskId) -> Result<TaskB64>;
+
+    #[rpc(name = "fetch_task_fields")]
+    fn fetch_task_fields(
+        &self,
+        id: TaskId,
+        fields: Option<Vec<String>>,
+    ) -> Result<serde_json::Map<String, serde_json::Value>>;
+
+    #[rpc(name = "fetch_task_data")]
+    fn fetch_task_data(&self, id: TaskId) -> Result<Vec<u8>>;
+
+    #[rpc(meta, name = "append_task_log")]
+    fn append_task_log(&self, meta: Self::Metadata, id: TaskId, line: String) -> Result<i64>;
+
+    #[rpc(name = "fetch_task_logs")]
+    fn fetch_task_logs(&self, id: TaskId, after_seq: Option<i64>) -> Result<Vec<TaskLogLine>>;
+
+    #[rpc(name = "tasks_have_same_data")]
+    fn tasks_have_same_data(&self, a: TaskId, b: TaskId) -> Result<bool>;
+
+    #[rpc(name = "list_tasks_by_multihash_prefix")]
+    fn list_tasks_by_multihash_prefix(&self, prefix: Vec<u8>) -> Result<Vec<TaskId>>;
+
+    #[rpc(name = "list_tasks_by_size")]
+    fn list_tasks_by_size(
+        &self,
+        min_bytes: Option<i64>,
+        max_bytes: Option<i64>,
+    ) -> Result<Vec<TaskId>>;
+
+    #[rpc(name = "verify_all_tasks")]
+    fn verify_all_tasks(&self, limit: Option<i64>) -> Result<Vec<TaskId>>;
+
+    #[rpc(name = "find_orphan_tasks")]
+    fn find_orphan_tasks(&self) -> Result<Vec<TaskId>>;
+
+    #[rpc(meta, name = "delete_orphan_tasks")]
+    fn delete_orphan_tasks(&self, meta: Self::Metadata, dry_run: Option<bool>) -> Result<i64>;
+
+    #[rpc(name = "dedup_stats")]
+    fn dedup_stats(&self) -> Result<DedupStats>;
+
+    #[rpc(name = "fetch_audit_log")]
+    fn fetch_audit_log(&self, limit: i64, offset: i64) -> Result<Vec<AuditEntry>>;
+
+    #[rpc(name = "pool_status")]
+    fn pool_status(&self) -> Result<PoolStatus>;
+
+    #[rpc(meta, name = "vacuum_analyze")]
+    fn vacuum_analyze(&self, meta: Self::Metadata) -> Result<()>;
+
+    #[rpc(name = "self_test")]
+    fn self_test(&self) -> Result<SelfTestReport>;
+
+    #[rpc(name = "request_status")]
+    fn request_status(&self) -> Result<RequestStatus>;
+
+    #[rpc(name = "version")]
+    fn version(&self) -> Result<VersionInfo>;
+
+    #[rpc(meta, name = "set_maintenance_mode")]
+    fn set_maintenance_mode(&self, meta: Self::Metadata, enabled: bool) -> Result<()>;
 }
 
-pub struct RpcImpl {
-    db_pool: r2d2::Pool<PostgresConnectionManager>,
+fn is_unique_violation(error: &postgres::Error) -> bool {
+    error.code() == Some(&postgres::error::UNIQUE_VIOLATION)
 }
 
-impl Rpc for RpcImpl {
-    fn create_profile(&self, base_name: String, name: String, json: String) -> Result<ProfileId> {
-        let conn = self.db_pool.get().unwrap();
+// The caller's content type, else one sniffed from the data, else DEFAULT_CONTENT_TYPE
+fn task_content_type(data: &[u8], content_type: Option<String>) -> String {
+    content_type
+        .or_else(|| infer::get(data).map(|kind| kind.mime_type().to_string()))
+        .unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_string())
+}
 
-        Ok(ProfileId(
-            conn.query(
-                "INSERT INTO profiles (base, name, json) VALUES ($1, $2, $3) RETURNING id",
-                &[&base_name, &name, &json],
-            )
-            .unwrap()
-            .iter()
-            .next()
-            .unwrap()
-            .get(0),
-        ))
+// Postgres refuses a negative LIMIT, so those ask for an empty page rather than failing the query
+fn list_limit(limit: i64) -> i64 {
+    limit.clamp(0, TASK_LIST_MAX_LIMIT)
+}
+
+fn check_offset(offset: i64) -> Result<()> {
+    if offset < 0 {
+        return Err(Error::invalid_params("offset must not be negative"));
     }
 
-    fn list_profiles(&self, by_base: Option<String>) -> Result<Vec<ProfileId>> {
-        let conn = self.db_pool.get().unwrap();
+    Ok(())
+}
 
-        if let Some(by_base) = by_base {
-            Ok(conn
-                .query("SELECT (id) FROM profiles WHERE base = $1", &[&by_base])
-                .unwrap()
-                .iter()
-                .map(|row| ProfileId(row.get(0)))
-                .collect())
-        } else {
-            Ok(conn
-                .query("SELECT (id) FROM profiles", &[])
-                .unwrap()
-                .iter()
-                .map(|row| ProfileId(row.get(0)))
-                .collect())
+// Postgres rejects a bad regular expression only when running the query
+fn check_pattern<T>(result: postgres::Result<T>) -> Result<T> {
+    match result {
+        Err(ref error) if error.code() == Some(&postgres::error::INVALID_REGULAR_EXPRESSION) => {
+            Err(Error::invalid_params(
+                "from is not a valid regular expression",
+            ))
         }
+        result => Ok(result.unwrap()),
     }
+}
 
-    fn fetch_profile(&self, id: ProfileId) -> Result<Profile> {
-        let conn = self.db_pool.get().unwrap();
+fn profile_from_row(row: &postgres::rows::Row) -> Profile {
+    Profile {
+        id: ProfileId(row.get("id")),
+        base: row.get("base"),
+        name: row.get("name"),
+        json: row.get("json"),
+        max_tasks: row.get("max_tasks"),
+        version: row.get("version"),
+        archived: row.get("archived"),
+        hash_algorithm: row.get("hash_algorithm"),
+        task_count: None,
+    }
+}
 
-        let rows = conn
-            .query("SELECT * FROM profiles WHERE id = $1", &[&id.0])
-            .unwrap();
-        let profile_row = rows.iter().next().unwrap();
+// Read-only transaction whose queries all see the database as of its first one
+fn read_snapshot(conn: &postgres::Connection) -> postgres::transaction::Transaction<'_> {
+    conn.transaction_with(
+        postgres::transaction::Config::new()
+            .isolation_level(postgres::transaction::IsolationLevel::RepeatableRead)
+            .read_only(true),
+    )
+    .unwrap()
+}
 
-        Ok(Profile {
-            id: ProfileId(profile_row.get("id")),
-            base: profile_row.get("base"),
-            name: profile_row.get("name"),
-            json: profile_row.get("json"),
-        })
+fn task_from_row(row: &postgres::rows::Row) -> Task {
+    Task {
+        id: TaskId(row.get("id")),
+        profile_id: ProfileId(row.get("profile_id")),
+        file_name: row.get("file_name"),
+        data: row.get("data"),
+        multihash: row.get("multihash"),
+        size_bytes: row.get("size_bytes"),
+        content_type: row.get("content_type"),
     }
+}
 
-    fn create_task(&self, profile: ProfileId, file_name: String, data: Vec<u8>) -> Result<TaskId> {
-        // Call before requesting conn from pool to not require two conns for one RPC call
-        let profile = self.fetch_profile(profile).unwrap();
+fn task_summary_from_row(row: &postgres::rows::Row) -> TaskSummary {
+    TaskSummary {
+        id: TaskId(row.get("id")),
+        profile_id: ProfileId(row.get("profile_id")),
+        file_name: row.get("file_name"),
+        size_bytes: row.get("size_bytes"),
+        content_type: row.get("content_type"),
+        created_at: row.get("created_at"),
+    }
+}
 
-        let conn = self.db_pool.get().unwrap();
+#[derive(Serialize, Default)]
+struct JsonDiff {
+    added: serde_json::Map<String, serde_json::Value>,
+    removed: serde_json::Map<String, serde_json::Value>,
+    changed: serde_json::Map<String, serde_json::Value>,
+}
 
-        Ok(TaskId(
-            conn.query(
-                "INSERT INTO tasks (profile_id, file_name, data, multihash) VALUES ($1, $2, $3, $4) RETURNING id",
-                &[&profile.id.0, &file_name, &data, &encode(Hash::SHA2256, &data).unwrap()],
-            )
-            .unwrap()
-            .iter()
-            .next()
-            .unwrap()
-            .get(0),
-        ))
-    }
+// Objects are compared key by key, keyed by dotted path; any other differing values, arrays
+// included, are reported as changed as a whole
+fn diff_json(path: &str, a: &serde_json::Value, b: &serde_json::Value, diff: &mut JsonDiff) {
+    match (a, b) {
+        (serde_json::Value::Object(a), serde_json::Value::Object(b)) => {
+            let child_path = |key: &str| {
+                if path.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{}.{}", path, key)
+                }
+            };
 
-    fn list_tasks(&self, by_profile: Option<ProfileId>) -> Result<Vec<TaskId>> {
-        let conn = self.db_pool.get().unwrap();
+            for (key, a_value) in a {
+                match b.get(key) {
+                    Some(b_value) => diff_json(&child_path(key), a_value, b_value, diff),
+                    None => {
+                        diff.removed.insert(child_path(key), a_value.clone());
+                    }
+                }
+            }
 
-        if let Some(by_profile) = by_profile {
-            Ok(conn
-                .query(
-                    "SELECT (id) FROM tasks WHERE profile_id = $1",
-                    &[&by_profile.0],
-                )
-                .unwrap()
-                .iter()
-                .map(|row| TaskId(row.get(0)))
-                .collect())
-        } else {
-            Ok(conn
-                .query("SELECT (id) FROM tasks", &[])
-                .unwrap()
-                .iter()
-                .map(|row| TaskId(row.get(0)))
-                .collect())
+            for (key, b_value) in b {
+                if !a.contains_key(key) {
+                    diff.added.insert(child_path(key), b_value.clone());
+                }
+            }
+        }
+        _ if a != b => {
+            let mut change = serde_json::Map::new();
+            change.insert("from".into(), a.clone());
+            change.insert("to".into(), b.clone());
+            diff.changed
+                .insert(path.to_string(), serde_json::Value::Object(change));
         }
+        _ => {}
     }
+}
 
-    fn fetch_task(&self, id: TaskId) -> Result<Task> {
-        let conn = self.db_pool.get().unwrap();
+// Objects are merged key by key; any other value in overlay, arrays included, replaces the base
+fn merge_json(base: &mut serde_json::Value, overlay: serde_json::Value) {
+    match (base, overlay) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(base_value) => merge_json(base_value, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
 
-        let rows = conn
-            .query("SELECT * FROM tasks WHERE id = $1", &[&id.0])
-            .unwrap();
-        let task_row = rows.iter().next().unwrap();
+// Accepts the multihash names, e.g. sha2-256 or keccak-512, ignoring case. Blake2 is left out as
+// the multihash crate can't encode it.
+pub fn parse_hash_algorithm(name: &str) -> Option<Hash> {
+    [
+        Hash::SHA1,
+        Hash::SHA2256,
+        Hash::SHA2512,
+        Hash::SHA3224,
+        Hash::SHA3256,
+        Hash::SHA3384,
+        Hash::SHA3512,
+        Hash::Keccak224,
+        Hash::Keccak256,
+        Hash::Keccak384,
+        Hash::Keccak512,
+    ]
+    .iter()
+    .cloned()
+    .find(|hash| hash.name().eq_ignore_ascii_case(name))
+}
 
-        Ok(Task {
-            id: TaskId(task_row.get("id")),
-            profile_id: ProfileId(task_row.get("profile_id")),
-            file_name: task_row.get("file_name"),
-            data: task_row.get("data"),
-            multihash: task_row.get("multihash"),
-        })
+fn multihash_matches(data: &[u8], multihash: &[u8]) -> bool {
+    match decode(multihash) {
+        Ok(decoded) => encode(decoded.alg, data)
+            .map(|expected| expected == multihash)
+            .unwrap_or(false),
+        Err(_) => false,
     }
 }
 
-mod config;
-use config::Config;
+// Runs one self_test step, recording its duration and any error
+fn timed_step<T>(
+    steps: &mut Vec<SelfTestStep>,
+    name: &str,
+    step: impl FnOnce() -> Result<T>,
+) -> Option<T> {
+    let started = Instant::now();
+    let result = step();
 
-fn main() {
-    let args = clap::App::new("coordinator")
-        .arg(
-            clap::Arg::with_name("config")
-                .short("c")
-                .long("config")
-                .max_values(1)
-                .takes_value(true)
-                .default_value("coordinator.yaml"),
-        )
-        .get_matches();
+    steps.push(SelfTestStep {
+        name: name.into(),
+        elapsed_ms: started.elapsed().as_millis() as u64,
+        error: result.as_ref().err().map(|error| error.message.clone()),
+    });
 
-    let config_path = args.value_of("config").unwrap();
-    let config: Config =
-        serde_yaml::from_reader(std::fs::File::open(config_path).expect("could not open config"))
-            .expect("could not parse config");
+    result.ok()
+}
 
-    let db_pool = r2d2::Pool::new(
-        PostgresConnectionManager::new(config.postgres_uri, TlsMode::None)
-            .expect("could not create PostgresConnectionManager"),
-    )
-    .expect("could not create r2d2::Pool");
+// Smallest byte string greater than every string starting with prefix, or None when there is
+// none (an empty prefix or one made only of 0xff bytes)
+fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut upper = prefix.to_vec();
 
-    db_pool
-        .get()
-        .unwrap()
-        .execute(
-            "CREATE TABLE IF NOT EXISTS profiles (
-                id BIGSERIAL PRIMARY KEY NOT NULL,
-                base VARCHAR(255) NOT NULL,
-                name VARCHAR(255) UNIQUE NOT NULL,
-                json JSONB NOT NULL
-            );",
-            &[],
-        )
-        .unwrap();
+    while let Some(last) = upper.pop() {
+        if last < 0xff {
+            upper.push(last + 1);
+            return Some(upper);
+        }
+    }
 
-    db_pool
-        .get()
-        .unwrap()
-        .execute(
-            "CREATE TABLE IF NOT EXISTS tasks (
-                id BIGSERIAL PRIMARY KEY NOT NULL,
-                profile_id BIGSERIAL NOT NULL,
-                file_name TEXT NOT NULL,
-                data BYTEA,
-                multihash BYTEA
-            );",
-            &[],
-        )
-        .unwrap();
+    None
+}
 
-    let rpc = RpcImpl { db_pool };
+// Regular files below dir, recursively. Symlinks are skipped so an import can't be pointed
+// outside the directory it was given.
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let file_type = std::fs::symlink_metadata(&path)?.file_type();
 
-    let mut io = IoHandler::new();
-    io.extend_with(rpc.to_delegate());
+        if file_type.is_dir() {
+            collect_files(&path, files)?;
+        } else if file_type.is_file() {
+            files.push(path);
+        }
+    }
 
-    let server = jsonrpc_tcp_server::ServerBuilder::new(io)
-        .start(&std::net::SocketAddr::from((
-            config.rpc_listen_address,
-            config.rpc_listen_port,
-        )))
-        .expect("jsonrpc tcp server failed to start");
+    Ok(())
+}
+
+// Opened without following a final symlink, so a file swapped for a link after collect_files
+// listed it fails to open rather than being read from wherever the link points
+fn read_regular_file(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?;
+
+    if !file.metadata()?.is_file() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is not a regular file", path.display()),
+        ));
+    }
+
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+
+    Ok(data)
+}
 
-    server.wait();
+#[derive(Clone)]
+pub struct RpcImpl {
+    db_pool: r2d2::Pool<PostgresConnectionManager>,
+    create_rate_limiter: Option<Arc<RateLimiter>>,
+    maintenance_mode: Arc<AtomicBool>,
+    import_root: Option<PathBuf>,
+    in_flight_requests: Arc<AtomicUsize>,
+    max_concurrent_requests: Option<usize>,
+    default_hash: Hash,
+    read_only: bool,
+    reject_duplicate_data: bool,
+    default_base: Option<String>,
+    slow_call_threshold: Option<Duration>,
+}
+
+impl RpcImpl {
+    fn check_create_rate(&self, meta: &Meta) -> Result<()> {
+        if let Some(create_rate_limiter) = &self.create_rate_limiter {
+            if !create_rate_limiter.try_acquire(meta.peer_addr.ip()) {
+                return Err(Code::RateLimited.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Code::ReadOnly.into());
+        }
+
+        if self.maintenance_mode.load(Ordering::SeqCst) {
+            return Err(Code::UnderMaintenance.into());
+        }
+
+        Ok(())
+    }
+
+    fn audited<T>(
+        &self,
+        meta: &Meta,
+        method: &str,
+        args: String,
+        call: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        // Every mutating RPC goes through here, so this is the one place writes are refused
+        self.check_writable()?;
+
+        self.recorded(meta, method, args, call)
+    }
+
+    // Writes the audit row without refusing anything, for admin calls that must work whatever
+    // the server's mode
+    fn recorded<T>(
+        &self,
+        meta: &Meta,
+        method: &str,
+        args: String,
+        call: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        let result = call();
+
+        // A read replica has nowhere to write the row
+        if self.read_only {
+            return result;
+        }
+
+        let outcome = match &result {
+            Ok(_) => "ok".to_string(),
+            Err(error) => error.message.clone(),
+        };
+
+        self.db_pool
+            .get()
+            .unwrap()
+            .execute(
+                "INSERT INTO audit_log (method, args, caller, outcome) VALUES ($1, $2, $3, $4)",
+                &[&method, &args, &meta.peer_addr.ip().to_string(), &outcome],
+            )
+            .unwrap();
+
+        result
+    }
+
+    // A versioned update touching no row either lost the race or targeted a missing profile
+    fn check_profile_updated(
+        &self,
+        conn: &dyn GenericConnection,
+        id: ProfileId,
+        updated: u64,
+    ) -> Result<()> {
+        if updated > 0 {
+            return Ok(());
+        }
+
+        if conn
+            .query("SELECT 1 FROM profiles WHERE id = $1", &[&id.0])
+            .unwrap()
+            .is_empty()
+        {
+            Err(Code::NotFound.into())
+        } else {
+            Err(Code::Conflict.into())
+        }
+    }
+
+    // With reject_duplicate_data set, refuses a multihash some task other than own_id already has
+    fn check_duplicate_data(
+        &self,
+        transaction: &postgres::transaction::Transaction,
+        multihash: &[u8],
+        own_id: Option<&TaskId>,
+    ) -> Result<()> {
+        if !self.reject_duplicate_data {
+            return Ok(());
+        }
+
+        // Existing rows may already share data, so there is no unique index to rely on. Instead
+        // writers of the same content take turns until commit, so two can't both miss each
+        // other's row.
+        transaction
+            .execute(
+                "SELECT pg_advisory_xact_lock(hashtextextended(encode($1, 'hex'), 0))",
+                &[&multihash],
+            )
+            .unwrap();
+
+        let rows = transaction
+            .query(
+                "SELECT id FROM tasks WHERE multihash = $1 AND ($2::BIGINT IS NULL OR id <> $2) ORDER BY id LIMIT 1",
+                &[&multihash, &own_id.map(|own_id| own_id.0)],
+            )
+            .unwrap();
+        if let Some(row) = rows.iter().next() {
+            let existing: i64 = row.get(0);
+            let mut error: Error = Code::DuplicateContent.into();
+            error.data = Some(serde_json::json!({ "task_id": existing }));
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    // The profile's own hash_algorithm, else the configured default
+    fn profile_hash(&self, hash_algorithm: Option<&str>) -> Hash {
+        hash_algorithm
+            .and_then(parse_hash_algorithm)
+            .unwrap_or(self.default_hash)
+    }
+
+    // Regular files below import_root/base_path in name order, with the directory their task names
+    // are relative to
+    fn import_files(&self, base_path: &str) -> Result<(PathBuf, Vec<PathBuf>)> {
+        let import_root = self.import_root.as_ref().ok_or(Code::Forbidden)?;
+        let read_error = |error: std::io::Error| Error::invalid_params(error.to_string());
+
+        // Resolving both paths first means neither .. nor a symlinked directory can reach
+        // outside the root
+        let root = import_root.canonicalize().map_err(read_error)?;
+        let dir = root
+            .join(base_path)
+            .canonicalize()
+            .map_err(|_| Code::NotFound)?;
+        if !dir.starts_with(&root) {
+            return Err(Code::Forbidden.into());
+        }
+
+        let mut files = Vec::new();
+        collect_files(&dir, &mut files).map_err(read_error)?;
+        files.sort();
+
+        Ok((dir, files))
+    }
+
+    // Inserts a task inside the caller's transaction, enforcing the profile's quota
+    fn insert_task(
+        &self,
+        transaction: &postgres::transaction::Transaction,
+        profile: &ProfileId,
+        file_name: &str,
+        data: &[u8],
+        content_type: Option<String>,
+    ) -> Result<TaskId> {
+        let content_type = task_content_type(data, content_type);
+
+        // Touching the profile first locks its row until commit, so it can't be deleted under
+        // the insert. Taking FOR SHARE before the UPDATE instead would deadlock concurrent
+        // creators.
+        let profile_rows = transaction
+            .query(
+                "UPDATE profiles SET updated_at = now() WHERE id = $1 RETURNING max_tasks, hash_algorithm",
+                &[&profile.0],
+            )
+            .unwrap();
+        let profile_row = profile_rows.iter().next().ok_or(Code::NotFound)?;
+        let max_tasks: Option<i64> = profile_row.get(0);
+        let hash_algorithm: Option<String> = profile_row.get(1);
+        let hash = self.profile_hash(hash_algorithm.as_deref());
+
+        if let Some(max_tasks) = max_tasks {
+            let task_count: i64 = transaction
+                .query(
+                    "SELECT COUNT(*) FROM tasks WHERE profile_id = $1",
+                    &[&profile.0],
+                )
+                .unwrap()
+                .iter()
+                .next()
+                .unwrap()
+                .get(0);
+
+            if task_count >= max_tasks {
+                return Err(Code::QuotaExceeded.into());
+            }
+        }
+
+        let multihash = encode(hash, data).unwrap();
+
+        self.check_duplicate_data(transaction, &multihash, None)?;
+
+        let id = TaskId(
+            transaction.query(
+                "INSERT INTO tasks (profile_id, file_name, data, multihash, size_bytes, content_type) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+                &[
+                    &profile.0,
+                    &file_name,
+                    &data,
+                    &multihash,
+                    &(data.len() as i64),
+                    &content_type,
+                ],
+            )
+            .unwrap()
+            .iter()
+            .next()
+            .unwrap()
+            .get(0),
+        );
+
+        Ok(id)
+    }
+
+    // An empty base_name stands for the configured default_base
+    fn profile_base<'a>(&'a self, base_name: &'a str) -> Result<&'a str> {
+        if !base_name.is_empty() {
+            return Ok(base_name);
+        }
+
+        self.default_base.as_deref().ok_or_else(|| {
+            Error::invalid_params("base_name is empty and no default_base is configured")
+        })
+    }
+
+    fn insert_profile(
+        &self,
+        conn: &dyn GenericConnection,
+        base_name: &str,
+        name: &str,
+        json: &str,
+    ) -> Result<ProfileId> {
+        let base_name = self.profile_base(base_name)?;
+        let json = self.json_with_base_defaults(conn, base_name, json)?;
+
+        let rows = match conn.query(
+            "INSERT INTO profiles (base, name, json) VALUES ($1, $2, $3::TEXT::JSONB) RETURNING id",
+            &[&base_name, &name, &json.to_string()],
+        ) {
+            Err(ref error) if is_unique_violation(error) => return Err(Code::DuplicateName.into()),
+            rows => rows.unwrap(),
+        };
+
+        Ok(ProfileId(rows.iter().next().unwrap().get(0)))
+    }
+
+    fn replace_profile_json(
+        &self,
+        conn: &dyn GenericConnection,
+        id: ProfileId,
+        json: &str,
+        expected_version: i32,
+    ) -> Result<()> {
+        if serde_json::from_str::<serde_json::Value>(json).is_err() {
+            return Err(Error::invalid_params("json is not valid JSON"));
+        }
+
+        let updated = conn
+            .execute(
+                "UPDATE profiles SET json = $2::TEXT::JSONB, version = version + 1, updated_at = now() WHERE id = $1 AND version = $3",
+                &[&id.0, &json, &expected_version],
+            )
+            .unwrap();
+
+        self.check_profile_updated(conn, id, updated)
+    }
+
+    // The JSON a new profile of this base is stored with: json merged over the base's defaults
+    fn json_with_base_defaults(
+        &self,
+        conn: &dyn GenericConnection,
+        base_name: &str,
+        json: &str,
+    ) -> Result<serde_json::Value> {
+        let json: serde_json::Value = serde_json::from_str(json)
+            .map_err(|_| Error::invalid_params("json is not valid JSON"))?;
+
+        let rows = conn
+            .query(
+                "SELECT json::TEXT FROM base_defaults WHERE base = $1",
+                &[&base_name],
+            )
+            .unwrap();
+
+        Ok(match rows.iter().next() {
+            Some(row) => {
+                let mut defaults: serde_json::Value =
+                    serde_json::from_str(&row.get::<_, String>(0)).unwrap();
+                merge_json(&mut defaults, json);
+                defaults
+            }
+            None => json,
+        })
+    }
+
+    fn set_profile_archived(
+        &self,
+        meta: &Meta,
+        method: &str,
+        id: ProfileId,
+        archived: bool,
+    ) -> Result<()> {
+        let args = format!("id={}", id.0);
+
+        self.audited(meta, method, args, || {
+            let conn = self.db_pool.get().unwrap();
+
+            let updated = conn
+                .execute(
+                    "UPDATE profiles SET archived = $2, version = version + 1, updated_at = now() WHERE id = $1",
+                    &[&id.0, &archived],
+                )
+                .unwrap();
+
+            if updated == 0 {
+                return Err(Code::NotFound.into());
+            }
+
+            Ok(())
+        })
+    }
+
+    fn profile_json(
+        &self,
+        conn: &postgres::Connection,
+        id: &ProfileId,
+    ) -> Result<serde_json::Value> {
+        let rows = conn
+            .query("SELECT json::TEXT FROM profiles WHERE id = $1", &[&id.0])
+            .unwrap();
+        let json: String = rows.iter().next().ok_or(Code::NotFound)?.get(0);
+
+        Ok(serde_json::from_str(&json).unwrap())
+    }
+}
+
+impl Rpc for RpcImpl {
+    type Metadata = Meta;
+
+    fn create_profile(
+        &self,
+        meta: Self::Metadata,
+        base_name: String,
+        name: String,
+        json: String,
+    ) -> Result<ProfileId> {
+        let args = format!("base={} name={}", base_name, name);
+
+        self.audited(&meta, "create_profile", args, || {
+            let conn = self.db_pool.get().unwrap();
+
+            self.insert_profile(&*conn, &base_name, &name, &json)
+        })
+    }
+
+    fn get_or_create_profile(
+        &self,
+        meta: Self::Metadata,
+        base_name: String,
+        name: String,
+        json: String,
+    ) -> Result<(ProfileId, bool)> {
+        let args = format!("base={} name={}", base_name, name);
+
+        self.audited(&meta, "get_or_create_profile", args, || {
+            let conn = self.db_pool.get().unwrap();
+
+            let base_name = self.profile_base(&base_name)?;
+            let json = self.json_with_base_defaults(&*conn, base_name, &json)?;
+
+            let rows = conn
+                .query(
+                    "INSERT INTO profiles (base, name, json) VALUES ($1, $2, $3::TEXT::JSONB) ON CONFLICT DO NOTHING RETURNING id",
+                    &[&base_name, &name, &json.to_string()],
+                )
+                .unwrap();
+            if let Some(row) = rows.iter().next() {
+                return Ok((ProfileId(row.get(0)), true));
+            }
+
+            // Names are unique regardless of case, so this finds the row the insert collided with
+            let rows = conn
+                .query(
+                    "SELECT id FROM profiles WHERE lower(name) = lower($1)",
+                    &[&name],
+                )
+                .unwrap();
+
+            Ok((ProfileId(rows.iter().next().unwrap().get(0)), false))
+        })
+    }
+
+    fn set_base_defaults(&self, meta: Self::Metadata, base: String, json: String) -> Result<()> {
+        let args = format!("base={} json_len={}", base, json.len());
+
+        self.audited(&meta, "set_base_defaults", args, || {
+            if serde_json::from_str::<serde_json::Value>(&json).is_err() {
+                return Err(Error::invalid_params("json is not valid JSON"));
+            }
+
+            let conn = self.db_pool.get().unwrap();
+
+            conn.execute(
+                "INSERT INTO base_defaults (base, json) VALUES ($1, $2::TEXT::JSONB)
+                ON CONFLICT (base) DO UPDATE SET json = EXCLUDED.json",
+                &[&base, &json],
+            )
+            .unwrap();
+
+            Ok(())
+        })
+    }
+
+    fn list_profiles(
+        &self,
+        by_base: Option<String>,
+        include_archived: Option<bool>,
+    ) -> Result<Vec<ProfileId>> {
+        let conn = self.db_pool.get().unwrap();
+        let include_archived = include_archived.unwrap_or(false);
+
+        if let Some(by_base) = by_base {
+            Ok(conn
+                .query(
+                    "SELECT (id) FROM profiles WHERE base = $1 AND ($2 OR NOT archived)",
+                    &[&by_base, &include_archived],
+                )
+                .unwrap()
+                .iter()
+                .map(|row| ProfileId(row.get(0)))
+                .collect())
+        } else {
+            Ok(conn
+                .query(
+                    "SELECT (id) FROM profiles WHERE $1 OR NOT archived",
+                    &[&include_archived],
+                )
+                .unwrap()
+                .iter()
+                .map(|row| ProfileId(row.get(0)))
+                .collect())
+        }
+    }
+
+    fn list_profiles_by_bases(
+        &self,
+        bases: Vec<String>,
+        include_archived: Option<bool>,
+    ) -> Result<Vec<ProfileId>> {
+        let conn = self.db_pool.get().unwrap();
+
+        Ok(conn
+            .query(
+                "SELECT (id) FROM profiles WHERE base = ANY($1) AND ($2 OR NOT archived) ORDER BY base, name",
+                &[&bases, &include_archived.unwrap_or(false)],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| ProfileId(row.get(0)))
+            .collect())
+    }
+
+    fn profile_exists(&self, id: ProfileId) -> Result<bool> {
+        let conn = self.db_pool.get().unwrap();
+
+        Ok(conn
+            .query(
+                "SELECT EXISTS (SELECT 1 FROM profiles WHERE id = $1)",
+                &[&id.0],
+            )
+            .unwrap()
+            .iter()
+            .next()
+            .unwrap()
+            .get(0))
+    }
+
+    fn fetch_profile(&self, id: ProfileId, with_task_count: Option<bool>) -> Result<Profile> {
+        let conn = self.db_pool.get().unwrap();
+
+        let rows = conn
+            .query(
+                "SELECT id, base, name, json::TEXT AS json, max_tasks, version, archived, hash_algorithm,
+                    CASE WHEN $2 THEN (SELECT COUNT(*) FROM tasks WHERE profile_id = profiles.id) END AS task_count
+                FROM profiles WHERE id = $1",
+                &[&id.0, &with_task_count.unwrap_or(false)],
+            )
+            .unwrap();
+        let profile_row = rows.iter().next().ok_or(Code::NotFound)?;
+
+        Ok(Profile {
+            task_count: profile_row.get("task_count"),
+            ..profile_from_row(&profile_row)
+        })
+    }
+
+    fn fetch_profile_with_tasks(&self, id: ProfileId, task_limit: i64) -> Result<ProfileWithTasks> {
+        let conn = self.db_pool.get().unwrap();
+
+        let transaction = read_snapshot(&conn);
+
+        let rows = transaction
+            .query(
+                "SELECT id, base, name, json::TEXT AS json, max_tasks, version, archived, hash_algorithm FROM profiles WHERE id = $1",
+                &[&id.0],
+            )
+            .unwrap();
+        let profile = profile_from_row(&rows.iter().next().ok_or(Code::NotFound)?);
+
+        let tasks = transaction
+            .query(
+                "SELECT id, profile_id, file_name, size_bytes, content_type, EXTRACT(EPOCH FROM created_at)::BIGINT AS created_at
+                FROM tasks WHERE profile_id = $1 ORDER BY id LIMIT $2",
+                &[&id.0, &list_limit(task_limit)],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| task_summary_from_row(&row))
+            .collect();
+
+        Ok(ProfileWithTasks { profile, tasks })
+    }
+
+    fn fetch_profile_json(&self, id: ProfileId) -> Result<String> {
+        let conn = self.db_pool.get().unwrap();
+
+        let rows = conn
+            .query("SELECT json::TEXT FROM profiles WHERE id = $1", &[&id.0])
+            .unwrap();
+
+        Ok(rows.iter().next().ok_or(Code::NotFound)?.get(0))
+    }
+
+    fn fetch_profile_by_name(&self, name: String) -> Result<Profile> {
+        let conn = self.db_pool.get().unwrap();
+
+        let rows = conn
+            .query(
+                "SELECT id, base, name, json::TEXT AS json, max_tasks, version, archived, hash_algorithm FROM profiles WHERE lower(name) = lower($1)",
+                &[&name],
+            )
+            .unwrap();
+        let profile_row = rows.iter().next().ok_or(Code::NotFound)?;
+
+        Ok(profile_from_row(&profile_row))
+    }
+
+    fn set_profile_max_tasks(
+        &self,
+        meta: Self::Metadata,
+        id: ProfileId,
+        max_tasks: Option<i64>,
+    ) -> Result<()> {
+        let args = format!("id={} max_tasks={:?}", id.0, max_tasks);
+
+        self.audited(&meta, "set_profile_max_tasks", args, || {
+            if max_tasks.is_some_and(|max_tasks| max_tasks < 0) {
+                return Err(Error::invalid_params("max_tasks must not be negative"));
+            }
+
+            let conn = self.db_pool.get().unwrap();
+
+            let updated = conn
+                .execute(
+                    "UPDATE profiles SET max_tasks = $2, version = version + 1, updated_at = now() WHERE id = $1",
+                    &[&id.0, &max_tasks],
+                )
+                .unwrap();
+
+            if updated == 0 {
+                return Err(Code::NotFound.into());
+            }
+
+            Ok(())
+        })
+    }
+
+    fn set_profile_hash_algorithm(
+        &self,
+        meta: Self::Metadata,
+        id: ProfileId,
+        hash_algorithm: Option<String>,
+    ) -> Result<()> {
+        let args = format!("id={} hash_algorithm={:?}", id.0, hash_algorithm);
+
+        self.audited(&meta, "set_profile_hash_algorithm", args, || {
+            if let Some(hash_algorithm) = &hash_algorithm {
+                parse_hash_algorithm(hash_algorithm)
+                    .ok_or_else(|| Error::invalid_params("unknown hash algorithm"))?;
+            }
+
+            let conn = self.db_pool.get().unwrap();
+
+            let updated = conn
+                .execute(
+                    "UPDATE profiles SET hash_algorithm = $2, version = version + 1, updated_at = now() WHERE id = $1",
+                    &[&id.0, &hash_algorithm],
+                )
+                .unwrap();
+
+            if updated == 0 {
+                return Err(Code::NotFound.into());
+            }
+
+            Ok(())
+        })
+    }
+
+    fn update_profile_json(
+        &self,
+        meta: Self::Metadata,
+        id: ProfileId,
+        json: String,
+        expected_version: i32,
+    ) -> Result<()> {
+        let args = format!(
+            "id={} expected_version={} json_len={}",
+            id.0,
+            expected_version,
+            json.len()
+        );
+
+        self.audited(&meta, "update_profile_json", args, || {
+            let conn = self.db_pool.get().unwrap();
+
+            self.replace_profile_json(&*conn, id, &json, expected_version)
+        })
+    }
+
+    fn rename_profile(
+        &self,
+        meta: Self::Metadata,
+        id: ProfileId,
+        name: String,
+        expected_version: i32,
+    ) -> Result<()> {
+        let args = format!(
+            "id={} name={} expected_version={}",
+            id.0, name, expected_version
+        );
+
+        self.audited(&meta, "rename_profile", args, || {
+            let conn = self.db_pool.get().unwrap();
+
+            let updated = match conn.execute(
+                "UPDATE profiles SET name = $2, version = version + 1, updated_at = now() WHERE id = $1 AND version = $3",
+                &[&id.0, &name, &expected_version],
+            ) {
+                Err(ref error) if is_unique_violation(error) => {
+                    return Err(Code::DuplicateName.into())
+                }
+                updated => updated.unwrap(),
+            };
+
+            self.check_profile_updated(&*conn, id, updated)
+        })
+    }
+
+    fn archive_profile(&self, meta: Self::Metadata, id: ProfileId) -> Result<()> {
+        self.set_profile_archived(&meta, "archive_profile", id, true)
+    }
+
+    fn unarchive_profile(&self, meta: Self::Metadata, id: ProfileId) -> Result<()> {
+        self.set_profile_archived(&meta, "unarchive_profile", id, false)
+    }
+
+    fn touch_profile(&self, meta: Self::Metadata, id: ProfileId) -> Result<()> {
+        let args = format!("id={}", id.0);
+
+        self.audited(&meta, "touch_profile", args, || {
+            let conn = self.db_pool.get().unwrap();
+
+            let updated = conn
+                .execute(
+                    "UPDATE profiles SET updated_at = now() WHERE id = $1",
+                    &[&id.0],
+                )
+                .unwrap();
+
+            if updated == 0 {
+                return Err(Code::NotFound.into());
+            }
+
+            Ok(())
+        })
+    }
+
+    fn diff_profiles(&self, a: ProfileId, b: ProfileId) -> Result<String> {
+        let conn = self.db_pool.get().unwrap();
+
+        let a = self.profile_json(&conn, &a)?;
+        let b = self.profile_json(&conn, &b)?;
+
+        let mut diff = JsonDiff::default();
+        diff_json("", &a, &b, &mut diff);
+
+        Ok(serde_json::to_string(&diff).unwrap())
+    }
+
+    fn create_task(
+        &self,
+        meta: Self::Metadata,
+        profile: ProfileId,
+        file_name: String,
+        data: Vec<u8>,
+        content_type: Option<String>,
+    ) -> Result<TaskId> {
+        self.check_create_rate(&meta)?;
+
+        let args = format!(
+            "profile={} file_name={} size={}",
+            profile.0,
+            file_name,
+            data.len()
+        );
+
+        self.audited(&meta, "create_task", args, || {
+            let conn = self.db_pool.get().unwrap();
+            let transaction = conn.transaction().unwrap();
+
+            let id = self.insert_task(&transaction, &profile, &file_name, &data, content_type)?;
+
+            transaction.commit().unwrap();
+
+            Ok(id)
+        })
+    }
+
+    fn import_directory(
+        &self,
+        meta: Self::Metadata,
+        profile: ProfileId,
+        base_path: String,
+        dry_run: Option<bool>,
+    ) -> Result<ImportResult> {
+        if dry_run.unwrap_or(false) {
+            let (_, files) = self.import_files(&base_path)?;
+
+            return Ok(ImportResult::DryRun(files.len() as i64));
+        }
+
+        let (dir, files) = self.import_files(&base_path)?;
+
+        // Each file becomes a task, so each one counts against the create rate
+        for _ in &files {
+            self.check_create_rate(&meta)?;
+        }
+
+        let args = format!("profile={} base_path={}", profile.0, base_path);
+
+        self.audited(&meta, "import_directory", args, || {
+            let read_error = |error: std::io::Error| Error::invalid_params(error.to_string());
+            let conn = self.db_pool.get().unwrap();
+            let transaction = conn.transaction().unwrap();
+
+            let mut ids = Vec::with_capacity(files.len());
+            for path in files {
+                let data = read_regular_file(&path).map_err(read_error)?;
+                let file_name = path.strip_prefix(&dir).unwrap().to_string_lossy();
+
+                ids.push(self.insert_task(&transaction, &profile, &file_name, &data, None)?);
+            }
+
+            transaction.commit().unwrap();
+
+            Ok(ImportResult::Created(ids))
+        })
+    }
+
+    fn update_task_data(
+        &self,
+        meta: Self::Metadata,
+        id: TaskId,
+        data: Vec<u8>,
+        content_type: Option<String>,
+    ) -> Result<()> {
+        let args = format!("id={} size={}", id.0, data.len());
+
+        self.audited(&meta, "update_task_data", args, || {
+            let conn = self.db_pool.get().unwrap();
+            let transaction = conn.transaction().unwrap();
+
+            let rows = transaction
+                .query(
+                    "SELECT t.multihash, p.hash_algorithm FROM tasks t LEFT JOIN profiles p ON p.id = t.profile_id
+                    WHERE t.id = $1 FOR UPDATE OF t",
+                    &[&id.0],
+                )
+                .unwrap();
+            let row = rows.iter().next().ok_or(Code::NotFound)?;
+            let multihash: Option<Vec<u8>> = row.get(0);
+            let hash_algorithm: Option<String> = row.get(1);
+
+            // Keep the algorithm the task was hashed with, so its address scheme doesn't change.
+            // A task without a readable multihash gets the one a new task of its profile would.
+            let alg = multihash
+                .as_ref()
+                .and_then(|multihash| decode(multihash).ok())
+                .map_or_else(
+                    || self.profile_hash(hash_algorithm.as_deref()),
+                    |decoded| decoded.alg,
+                );
+            let multihash = encode(alg, &data).unwrap();
+
+            self.check_duplicate_data(&transaction, &multihash, Some(&id))?;
+
+            // The stored type usually came from the old bytes, so it is set again as on creation
+            transaction
+                .execute(
+                    "UPDATE tasks SET data = $2, multihash = $3, size_bytes = $4, content_type = $5 WHERE id = $1",
+                    &[
+                        &id.0,
+                        &data,
+                        &multihash,
+                        &(data.len() as i64),
+                        &task_content_type(&data, content_type),
+                    ],
+                )
+                .unwrap();
+
+            transaction.commit().unwrap();
+
+            Ok(())
+        })
+    }
+
+    // Only the first match of from in each name is replaced, with \1 and so on in to referring
+    // to its groups, as in Postgres' regexp_replace
+    fn rename_tasks_by_pattern(
+        &self,
+        meta: Self::Metadata,
+        profile: ProfileId,
+        from: String,
+        to: String,
+        dry_run: Option<bool>,
+    ) -> Result<i64> {
+        if dry_run.unwrap_or(false) {
+            let conn = self.db_pool.get().unwrap();
+
+            let rows = check_pattern(conn.query(
+                "SELECT COUNT(*) FROM tasks WHERE profile_id = $1 AND regexp_replace(file_name, $2, $3) <> file_name",
+                &[&profile.0, &from, &to],
+            ))?;
+
+            return Ok(rows.iter().next().unwrap().get(0));
+        }
+
+        let args = format!("profile={} from={} to={}", profile.0, from, to);
+
+        self.audited(&meta, "rename_tasks_by_pattern", args, || {
+            let conn = self.db_pool.get().unwrap();
+
+            let renamed = check_pattern(conn.execute(
+                "UPDATE tasks SET file_name = regexp_replace(file_name, $2, $3) WHERE profile_id = $1 AND regexp_replace(file_name, $2, $3) <> file_name",
+                &[&profile.0, &from, &to],
+            ))?;
+
+            Ok(renamed as i64)
+        })
+    }
+
+    fn atomic_batch(
+        &self,
+        meta: Self::Metadata,
+        ops: Vec<Operation>,
+    ) -> Result<Vec<OperationResult>> {
+        for op in &ops {
+            if let Operation::CreateTask { .. } = op {
+                self.check_create_rate(&meta)?;
+            }
+        }
+
+        let args = format!("ops={}", ops.len());
+
+        self.audited(&meta, "atomic_batch", args, || {
+            let conn = self.db_pool.get().unwrap();
+            let transaction = conn.transaction().unwrap();
+
+            let mut results: Vec<OperationResult> = Vec::with_capacity(ops.len());
+            for (index, op) in ops.into_iter().enumerate() {
+                let result = match op {
+                    Operation::CreateProfile {
+                        base_name,
+                        name,
+                        json,
+                    } => self
+                        .insert_profile(&transaction, &base_name, &name, &json)
+                        .map(|id| OperationResult::CreateProfile { id }),
+                    Operation::UpdateProfileJson {
+                        id,
+                        json,
+                        expected_version,
+                    } => self
+                        .replace_profile_json(&transaction, id, &json, expected_version)
+                        .map(|()| OperationResult::UpdateProfileJson),
+                    Operation::CreateTask {
+                        profile,
+                        file_name,
+                        data,
+                        content_type,
+                    } => {
+                        let profile = match profile {
+                            BatchProfile::Id(id) => Ok(id),
+                            BatchProfile::CreatedBy { created_by } => {
+                                match results.get(created_by) {
+                                    Some(OperationResult::CreateProfile { id }) => {
+                                        Ok(ProfileId(id.0))
+                                    }
+                                    _ => Err(Error::invalid_params(
+                                        "created_by must name an earlier create_profile",
+                                    )),
+                                }
+                            }
+                        };
+
+                        profile
+                            .and_then(|profile| {
+                                self.insert_task(
+                                    &transaction,
+                                    &profile,
+                                    &file_name,
+                                    &data,
+                                    content_type,
+                                )
+                            })
+                            .map(|id| OperationResult::CreateTask { id })
+                    }
+                };
+
+                // Dropping the transaction on the way out rolls back everything before this
+                match result {
+                    Ok(result) => results.push(result),
+                    Err(mut error) => {
+                        let mut data = match error.data.take() {
+                            Some(serde_json::Value::Object(data)) => data,
+                            _ => serde_json::Map::new(),
+                        };
+                        data.insert("operation".into(), index.into());
+                        error.data = Some(serde_json::Value::Object(data));
+                        return Err(error);
+                    }
+                }
+            }
+
+            transaction.commit().unwrap();
+
+            Ok(results)
+        })
+    }
+
+    fn list_tasks(&self, by_profile: Option<ProfileId>) -> Result<Vec<TaskId>> {
+        let conn = self.db_pool.get().unwrap();
+
+        if let Some(by_profile) = by_profile {
+            Ok(conn
+                .query(
+                    "SELECT (id) FROM tasks WHERE profile_id = $1",
+                    &[&by_profile.0],
+                )
+                .unwrap()
+                .iter()
+                .map(|row| TaskId(row.get(0)))
+                .collect())
+        } else {
+            Ok(conn
+                .query("SELECT (id) FROM tasks", &[])
+                .unwrap()
+                .iter()
+                .map(|row| TaskId(row.get(0)))
+                .collect())
+        }
+    }
+
+    fn list_profile_tasks(
+        &self,
+        profile: ProfileId,
+        limit: i64,
+        offset: i64,
+    ) -> Result<ListPage<TaskSummary>> {
+        check_offset(offset)?;
+
+        let conn = self.db_pool.get().unwrap();
+
+        // One snapshot for both queries, so the total matches the page it comes with
+        let transaction = read_snapshot(&conn);
+
+        let items = transaction
+            .query(
+                "SELECT id, profile_id, file_name, size_bytes, content_type, EXTRACT(EPOCH FROM created_at)::BIGINT AS created_at
+                FROM tasks WHERE profile_id = $1 ORDER BY id LIMIT $2 OFFSET $3",
+                &[&profile.0, &list_limit(limit), &offset],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| task_summary_from_row(&row))
+            .collect();
+
+        let total = transaction
+            .query(
+                "SELECT COUNT(*) FROM tasks WHERE profile_id = $1",
+                &[&profile.0],
+            )
+            .unwrap()
+            .iter()
+            .next()
+            .unwrap()
+            .get(0);
+
+        Ok(ListPage { items, total })
+    }
+
+    fn list_recent_tasks(&self, limit: i64) -> Result<Vec<TaskSummary>> {
+        let conn = self.db_pool.get().unwrap();
+
+        Ok(conn
+            .query(
+                "SELECT id, profile_id, file_name, size_bytes, content_type, EXTRACT(EPOCH FROM created_at)::BIGINT AS created_at
+                FROM tasks ORDER BY created_at DESC, id DESC LIMIT $1",
+                &[&list_limit(limit)],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| task_summary_from_row(&row))
+            .collect())
+    }
+
+    // With include_data false the tasks come back with empty data, so a mirror can find new ids
+    // without pulling every blob
+    fn tasks_since(
+        &self,
+        after_id: TaskId,
+        limit: i64,
+        include_data: Option<bool>,
+    ) -> Result<Vec<Task>> {
+        let conn = self.db_pool.get().unwrap();
+
+        Ok(conn
+            .query(
+                "SELECT id, profile_id, file_name, CASE WHEN $3 THEN data ELSE ''::BYTEA END AS data, multihash, size_bytes, content_type
+                FROM tasks WHERE id > $1 ORDER BY id LIMIT $2",
+                &[&after_id.0, &list_limit(limit), &include_data.unwrap_or(true)],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| task_from_row(&row))
+            .collect())
+    }
+
+    // Without include_data the tasks come back with empty data, so exports can list them cheaply
+    fn fetch_task_range(
+        &self,
+        from_id: TaskId,
+        to_id: TaskId,
+        include_data: bool,
+    ) -> Result<Vec<Task>> {
+        if to_id.0.saturating_sub(from_id.0) >= TASK_LIST_MAX_LIMIT {
+            return Err(Error::invalid_params(format!(
+                "range spans more than {} ids",
+                TASK_LIST_MAX_LIMIT
+            )));
+        }
+
+        let conn = self.db_pool.get().unwrap();
+
+        Ok(conn
+            .query(
+                "SELECT id, profile_id, file_name, CASE WHEN $3 THEN data ELSE ''::BYTEA END AS data, multihash, size_bytes, content_type
+                FROM tasks WHERE id BETWEEN $1 AND $2 ORDER BY id",
+                &[&from_id.0, &to_id.0, &include_data],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| task_from_row(&row))
+            .collect())
+    }
+
+    fn task_exists(&self, id: TaskId) -> Result<bool> {
+        let conn = self.db_pool.get().unwrap();
+
+        Ok(conn
+            .query(
+                "SELECT EXISTS (SELECT 1 FROM tasks WHERE id = $1)",
+                &[&id.0],
+            )
+            .unwrap()
+            .iter()
+            .next()
+            .unwrap()
+            .get(0))
+    }
+
+    fn fetch_task(&self, id: TaskId) -> Result<Task> {
+        let conn = self.db_pool.get().unwrap();
+
+        let rows = conn
+            .query("SELECT * FROM tasks WHERE id = $1", &[&id.0])
+            .unwrap();
+        let task_row = rows.iter().next().ok_or(Code::NotFound)?;
+
+        Ok(task_from_row(&task_row))
+    }
+
+    fn fetch_task_b64(&self, id: TaskId) -> Result<TaskB64> {
+        let conn = self.db_pool.get().unwrap();
+
+        let rows = conn
+            .query("SELECT * FROM tasks WHERE id = $1", &[&id.0])
+            .unwrap();
+        let task = task_from_row(&rows.iter().next().ok_or(Code::NotFound)?);
+
+        Ok(TaskB64 {
+            id: task.id,
+            profile_id: task.profile_id,
+            file_name: task.file_name,
+            data: BASE64_STANDARD.encode(task.data),
+            multihash: BASE64_STANDARD.encode(task.multihash),
+            size_bytes: task.size_bytes,
+            content_type: task.content_type,
+        })
+    }
+
+    fn fetch_task_fields(
+        &self,
+        id: TaskId,
+        fields: Option<Vec<String>>,
+    ) -> Result<serde_json::Map<String, serde_json::Value>> {
+        let fields =
+            fields.unwrap_or_else(|| TASK_FIELDS.iter().map(|field| field.to_string()).collect());
+
+        // Field names end up in the query, so only known columns get that far
+        if let Some(unknown) = fields
+            .iter()
+            .find(|field| !TASK_FIELDS.contains(&field.as_str()))
+        {
+            return Err(Error::invalid_params(format!("unknown field {}", unknown)));
+        }
+
+        // id leads the select list so an empty selection still tells a missing task apart
+        let mut columns = vec!["id".to_string()];
+        columns.extend(fields.iter().map(|field| match field.as_str() {
+            "created_at" => "EXTRACT(EPOCH FROM created_at)::BIGINT".to_string(),
+            field => field.to_string(),
+        }));
+
+        let conn = self.db_pool.get().unwrap();
+
+        let rows = conn
+            .query(
+                &format!("SELECT {} FROM tasks WHERE id = $1", columns.join(", ")),
+                &[&id.0],
+            )
+            .unwrap();
+        let row = rows.iter().next().ok_or(Code::NotFound)?;
+
+        Ok(fields
+            .into_iter()
+            .enumerate()
+            .map(|(i, field)| {
+                let column = i + 1;
+                let value = match field.as_str() {
+                    "file_name" | "content_type" => {
+                        serde_json::to_value(row.get::<_, String>(column))
+                    }
+                    "data" | "multihash" => {
+                        serde_json::to_value(row.get::<_, Option<Vec<u8>>>(column))
+                    }
+                    _ => serde_json::to_value(row.get::<_, Option<i64>>(column)),
+                };
+
+                (field, value.unwrap())
+            })
+            .collect())
+    }
+
+    fn fetch_task_data(&self, id: TaskId) -> Result<Vec<u8>> {
+        let conn = self.db_pool.get().unwrap();
+
+        let rows = conn
+            .query("SELECT data FROM tasks WHERE id = $1", &[&id.0])
+            .unwrap();
+        let data: Option<Vec<u8>> = rows.iter().next().ok_or(Code::NotFound)?.get(0);
+
+        Ok(data.unwrap_or_default())
+    }
+
+    fn append_task_log(&self, meta: Self::Metadata, id: TaskId, line: String) -> Result<i64> {
+        let args = format!("id={} line_len={}", id.0, line.len());
+
+        self.audited(&meta, "append_task_log", args, || {
+            let conn = self.db_pool.get().unwrap();
+            let transaction = conn.transaction().unwrap();
+
+            // Locking the task serializes appends, so sequence numbers and the size cap hold
+            // under concurrent writers
+            if transaction
+                .query("SELECT 1 FROM tasks WHERE id = $1 FOR UPDATE", &[&id.0])
+                .unwrap()
+                .is_empty()
+            {
+                return Err(Code::NotFound.into());
+            }
+
+            let rows = transaction
+                .query(
+                    "SELECT COALESCE(MAX(seq), 0), COALESCE(SUM(octet_length(line)), 0)::BIGINT FROM task_logs WHERE task_id = $1",
+                    &[&id.0],
+                )
+                .unwrap();
+            let row = rows.iter().next().unwrap();
+            let last_seq: i64 = row.get(0);
+            let log_bytes: i64 = row.get(1);
+
+            if log_bytes + line.len() as i64 > TASK_LOG_MAX_BYTES {
+                return Err(Code::QuotaExceeded.into());
+            }
+
+            let seq = last_seq + 1;
+            transaction
+                .execute(
+                    "INSERT INTO task_logs (task_id, seq, line) VALUES ($1, $2, $3)",
+                    &[&id.0, &seq, &line],
+                )
+                .unwrap();
+
+            transaction.commit().unwrap();
+
+            Ok(seq)
+        })
+    }
+
+    fn fetch_task_logs(&self, id: TaskId, after_seq: Option<i64>) -> Result<Vec<TaskLogLine>> {
+        let conn = self.db_pool.get().unwrap();
+
+        // Checked in the same snapshot, so a missing task isn't mistaken for one without output
+        let transaction = read_snapshot(&conn);
+
+        if transaction
+            .query("SELECT 1 FROM tasks WHERE id = $1", &[&id.0])
+            .unwrap()
+            .is_empty()
+        {
+            return Err(Code::NotFound.into());
+        }
+
+        Ok(transaction
+            .query(
+                "SELECT seq, EXTRACT(EPOCH FROM at)::BIGINT AS at, line FROM task_logs WHERE task_id = $1 AND seq > $2 ORDER BY seq",
+                &[&id.0, &after_seq.unwrap_or(0)],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| TaskLogLine {
+                seq: row.get("seq"),
+                at: row.get("at"),
+                line: row.get("line"),
+            })
+            .collect())
+    }
+
+    fn tasks_have_same_data(&self, a: TaskId, b: TaskId) -> Result<bool> {
+        let conn = self.db_pool.get().unwrap();
+
+        let multihash = |id: TaskId| -> Result<Option<Vec<u8>>> {
+            let rows = conn
+                .query("SELECT multihash FROM tasks WHERE id = $1", &[&id.0])
+                .unwrap();
+            Ok(rows.iter().next().ok_or(Code::NotFound)?.get(0))
+        };
+
+        // Tasks without a stored hash can't be shown to hold the same bytes
+        let (a, b) = (multihash(a)?, multihash(b)?);
+        Ok(a.is_some() && a == b)
+    }
+
+    fn list_tasks_by_multihash_prefix(&self, prefix: Vec<u8>) -> Result<Vec<TaskId>> {
+        let conn = self.db_pool.get().unwrap();
+
+        let rows = match prefix_upper_bound(&prefix) {
+            Some(upper) => conn
+                .query(
+                    "SELECT id FROM tasks WHERE multihash >= $1 AND multihash < $2 ORDER BY id",
+                    &[&prefix, &upper],
+                )
+                .unwrap(),
+            None => conn
+                .query(
+                    "SELECT id FROM tasks WHERE multihash >= $1 ORDER BY id",
+                    &[&prefix],
+                )
+                .unwrap(),
+        };
+
+        Ok(rows.iter().map(|row| TaskId(row.get(0))).collect())
+    }
+
+    fn list_tasks_by_size(
+        &self,
+        min_bytes: Option<i64>,
+        max_bytes: Option<i64>,
+    ) -> Result<Vec<TaskId>> {
+        let conn = self.db_pool.get().unwrap();
+
+        Ok(conn
+            .query(
+                "SELECT id FROM tasks WHERE size_bytes >= COALESCE($1, size_bytes) AND size_bytes <= COALESCE($2, size_bytes) ORDER BY size_bytes DESC, id",
+                &[&min_bytes, &max_bytes],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| TaskId(row.get(0)))
+            .collect())
+    }
+
+    fn verify_all_tasks(&self, limit: Option<i64>) -> Result<Vec<TaskId>> {
+        let conn = self.db_pool.get().unwrap();
+
+        let mut mismatched = Vec::new();
+        let mut last_id = 0i64;
+        let mut remaining = limit;
+
+        // Walk the table in id order, one short query per batch, so the audit never holds a
+        // transaction open over the whole store
+        loop {
+            let batch_size = remaining.map_or(VERIFY_BATCH_SIZE, |remaining| {
+                remaining.min(VERIFY_BATCH_SIZE)
+            });
+            if batch_size <= 0 {
+                break;
+            }
+
+            let rows = conn
+                .query(
+                    "SELECT id, data, multihash FROM tasks WHERE id > $1 ORDER BY id LIMIT $2",
+                    &[&last_id, &batch_size],
+                )
+                .unwrap();
+            if rows.is_empty() {
+                break;
+            }
+
+            for row in rows.iter() {
+                last_id = row.get(0);
+                let data: Option<Vec<u8>> = row.get(1);
+                let multihash: Option<Vec<u8>> = row.get(2);
+
+                let matches = match (data, multihash) {
+                    (Some(data), Some(multihash)) => multihash_matches(&data, &multihash),
+                    _ => false,
+                };
+                if !matches {
+                    mismatched.push(TaskId(last_id));
+                }
+            }
+
+            remaining = remaining.map(|remaining| remaining - rows.len() as i64);
+        }
+
+        Ok(mismatched)
+    }
+
+    fn find_orphan_tasks(&self) -> Result<Vec<TaskId>> {
+        let conn = self.db_pool.get().unwrap();
+
+        Ok(conn
+            .query(
+                "SELECT id FROM tasks t WHERE NOT EXISTS (SELECT 1 FROM profiles p WHERE p.id = t.profile_id) ORDER BY id",
+                &[],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| TaskId(row.get(0)))
+            .collect())
+    }
+
+    fn delete_orphan_tasks(&self, meta: Self::Metadata, dry_run: Option<bool>) -> Result<i64> {
+        if dry_run.unwrap_or(false) {
+            let conn = self.db_pool.get().unwrap();
+
+            return Ok(conn
+                .query(
+                    "SELECT COUNT(*) FROM tasks t WHERE NOT EXISTS (SELECT 1 FROM profiles p WHERE p.id = t.profile_id)",
+                    &[],
+                )
+                .unwrap()
+                .iter()
+                .next()
+                .unwrap()
+                .get(0));
+        }
+
+        self.audited(&meta, "delete_orphan_tasks", String::new(), || {
+            let conn = self.db_pool.get().unwrap();
+
+            let deleted = conn
+                .execute(
+                    "DELETE FROM tasks t WHERE NOT EXISTS (SELECT 1 FROM profiles p WHERE p.id = t.profile_id)",
+                    &[],
+                )
+                .unwrap();
+
+            Ok(deleted as i64)
+        })
+    }
+
+    // Tasks without a stored hash can't be shown to share data, so each counts as its own blob
+    fn dedup_stats(&self) -> Result<DedupStats> {
+        let conn = self.db_pool.get().unwrap();
+
+        let rows = conn
+            .query(
+                "SELECT COUNT(*), COUNT(DISTINCT multihash), COALESCE(SUM(size_bytes), 0)::BIGINT,
+                    (SELECT COALESCE(SUM(size_bytes), 0)::BIGINT FROM (
+                        SELECT MAX(size_bytes) AS size_bytes FROM tasks WHERE multihash IS NOT NULL GROUP BY multihash
+                        UNION ALL
+                        SELECT size_bytes FROM tasks WHERE multihash IS NULL
+                    ) blobs)
+                FROM tasks",
+                &[],
+            )
+            .unwrap();
+        let row = rows.iter().next().unwrap();
+
+        let total_bytes: i64 = row.get(2);
+        let distinct_bytes: i64 = row.get(3);
+
+        Ok(DedupStats {
+            total_tasks: row.get(0),
+            distinct_multihashes: row.get(1),
+            total_bytes,
+            distinct_bytes,
+            bytes_saved: total_bytes - distinct_bytes,
+        })
+    }
+
+    fn fetch_audit_log(&self, limit: i64, offset: i64) -> Result<Vec<AuditEntry>> {
+        check_offset(offset)?;
+
+        let conn = self.db_pool.get().unwrap();
+
+        Ok(conn
+            .query(
+                "SELECT id, EXTRACT(EPOCH FROM at)::BIGINT AS at, method, args, caller, outcome FROM audit_log ORDER BY id DESC LIMIT $1 OFFSET $2",
+                &[&list_limit(limit), &offset],
+            )
+            .unwrap()
+            .iter()
+            .map(|row| AuditEntry {
+                id: row.get("id"),
+                at: row.get("at"),
+                method: row.get("method"),
+                args: row.get("args"),
+                caller: row.get("caller"),
+                outcome: row.get("outcome"),
+            })
+            .collect())
+    }
+
+    fn pool_status(&self) -> Result<PoolStatus> {
+        let state = self.db_pool.state();
+
+        Ok(PoolStatus {
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+            max_size: self.db_pool.max_size(),
+        })
+    }
+
+    // VACUUM changes no application data, so it still runs in maintenance mode, which is when
+    // operators schedule it. A read replica can't vacuum.
+    fn vacuum_analyze(&self, meta: Self::Metadata) -> Result<()> {
+        if self.read_only {
+            return Err(Code::ReadOnly.into());
+        }
+
+        self.recorded(&meta, "vacuum_analyze", String::new(), || {
+            // VACUUM refuses to run inside a transaction block, so each table gets its own
+            // statement on a plain pooled connection
+            let conn = self.db_pool.get().unwrap();
+            let started = Instant::now();
+
+            for table in &["tasks", "profiles"] {
+                conn.batch_execute(&format!("VACUUM ANALYZE {}", table))
+                    .unwrap();
+            }
+
+            eprintln!("vacuum_analyze took {} ms", started.elapsed().as_millis());
+
+            Ok(())
+        })
+    }
+
+    // Everything happens in one transaction that is never committed, so the test rows vanish
+    // however far it gets, even if a step panics
+    fn self_test(&self) -> Result<SelfTestReport> {
+        self.check_writable()?;
+
+        let conn = self.db_pool.get().unwrap();
+        let transaction = conn.transaction().unwrap();
+
+        // Unique data keeps reject_duplicate_data from refusing the task
+        let nonce = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let name = format!("self-test-{}", nonce);
+        let data = format!("self test {}", nonce).into_bytes();
+
+        let mut steps = Vec::new();
+
+        (|| {
+            let profile = timed_step(&mut steps, "create_profile", || {
+                self.insert_profile(&transaction, SELF_TEST_BASE, &name, "{}")
+            })?;
+
+            let id = timed_step(&mut steps, "create_task", || {
+                self.insert_task(&transaction, &profile, &name, &data, None)
+            })?;
+
+            let task = timed_step(&mut steps, "fetch_task", || {
+                let rows = transaction
+                    .query("SELECT * FROM tasks WHERE id = $1", &[&id.0])
+                    .unwrap();
+
+                Ok(task_from_row(&rows.iter().next().ok_or(Code::NotFound)?))
+            })?;
+
+            timed_step(&mut steps, "verify_multihash", || {
+                if task.data == data && multihash_matches(&task.data, &task.multihash) {
+                    Ok(())
+                } else {
+                    Err(Error {
+                        message: "fetched task does not match its data".into(),
+                        ..Error::internal_error()
+                    })
+                }
+            })
+        })();
+
+        timed_step(&mut steps, "cleanup", || {
+            transaction.set_rollback();
+            transaction.finish().unwrap();
+
+            Ok(())
+        });
+
+        Ok(SelfTestReport {
+            ok: steps.iter().all(|step| step.error.is_none()),
+            steps,
+        })
+    }
+
+    fn request_status(&self) -> Result<RequestStatus> {
+        Ok(RequestStatus {
+            in_flight: self.in_flight_requests.load(Ordering::SeqCst),
+            max_concurrent: self.max_concurrent_requests,
+        })
+    }
+
+    fn version(&self) -> Result<VersionInfo> {
+        Ok(VersionInfo {
+            version: env!("CARGO_PKG_VERSION").into(),
+            maintenance_mode: self.maintenance_mode.load(Ordering::SeqCst),
+            read_only: self.read_only,
+        })
+    }
+
+    // Not refused while maintenance mode is on, or it could never be turned off again
+    fn set_maintenance_mode(&self, meta: Self::Metadata, enabled: bool) -> Result<()> {
+        let args = format!("enabled={}", enabled);
+
+        self.recorded(&meta, "set_maintenance_mode", args, || {
+            self.maintenance_mode.store(enabled, Ordering::SeqCst);
+
+            Ok(())
+        })
+    }
+}
+
+// Names every pooled connection in pg_stat_activity and points it at the configured schema, so
+// queries never name it
+#[derive(Debug)]
+struct ConnectionSetup {
+    application_name: String,
+    schema_name: Option<String>,
+}
+
+impl r2d2::CustomizeConnection<postgres::Connection, postgres::Error> for ConnectionSetup {
+    fn on_acquire(
+        &self,
+        conn: &mut postgres::Connection,
+    ) -> std::result::Result<(), postgres::Error> {
+        conn.batch_execute(&format!(
+            "SET application_name TO {}",
+            quote_literal(&self.application_name)
+        ))?;
+
+        if let Some(schema_name) = &self.schema_name {
+            conn.batch_execute(&format!(
+                "SET search_path TO {}",
+                quote_identifier(schema_name)
+            ))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+fn quote_literal(literal: &str) -> String {
+    format!("'{}'", literal.replace('\'', "''"))
+}
+
+// Calls refused as busy never reach the slow call log, so it only times calls that ran
+pub type RpcMiddleware = (ConcurrencyLimit, SlowCallLog);
+
+fn rpc_handler(rpc: RpcImpl) -> MetaIoHandler<Meta, RpcMiddleware> {
+    let mut io = MetaIoHandler::with_middleware((
+        ConcurrencyLimit::new(rpc.in_flight_requests.clone(), rpc.max_concurrent_requests),
+        SlowCallLog::new(rpc.slow_call_threshold),
+    ));
+    io.extend_with(rpc.to_delegate());
+    io
+}
+
+mod config;
+use config::Config;
+
+mod error;
+use error::Code;
+
+mod msgpack;
+
+mod concurrency;
+use concurrency::ConcurrencyLimit;
+
+mod slow_log;
+use slow_log::SlowCallLog;
+
+mod client;
+use client::Client;
+
+mod rate_limit;
+use rate_limit::RateLimiter;
+
+fn main() {
+    let args = clap::App::new("coordinator")
+        .arg(
+            clap::Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .max_values(1)
+                .takes_value(true)
+                .default_value("coordinator.yaml"),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("submit")
+                .about("Submits a file as a new task")
+                .arg(
+                    clap::Arg::with_name("profile")
+                        .long("profile")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    clap::Arg::with_name("file")
+                        .long("file")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(addr_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("create-profile")
+                .about("Creates a new profile")
+                .arg(
+                    clap::Arg::with_name("base")
+                        .long("base")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    clap::Arg::with_name("name")
+                        .long("name")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    clap::Arg::with_name("json-file")
+                        .long("json-file")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(addr_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("list-tasks")
+                .about("Lists task ids, optionally of a single profile")
+                .arg(
+                    clap::Arg::with_name("profile")
+                        .long("profile")
+                        .takes_value(true),
+                )
+                .arg(addr_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("fetch-task")
+                .about("Writes a task's data to a file after verifying its multihash")
+                .arg(
+                    clap::Arg::with_name("id")
+                        .long("id")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    clap::Arg::with_name("out")
+                        .long("out")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(addr_arg()),
+        )
+        .get_matches();
+
+    match args.subcommand() {
+        ("submit", Some(args)) => submit(args),
+        ("create-profile", Some(args)) => create_profile(args),
+        ("list-tasks", Some(args)) => list_tasks(args),
+        ("fetch-task", Some(args)) => fetch_task(args),
+        _ => serve(args.value_of("config").unwrap()),
+    }
+}
+
+fn addr_arg<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("addr")
+        .long("addr")
+        .takes_value(true)
+        .default_value("127.0.0.1:6000")
+}
+
+fn exit_on_error<T>(result: Result<T>) -> T {
+    result.unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        std::process::exit(1)
+    })
+}
+
+fn submit(args: &clap::ArgMatches) {
+    let profile = ProfileId(
+        args.value_of("profile")
+            .unwrap()
+            .parse()
+            .expect("profile must be a numeric id"),
+    );
+    let path = std::path::Path::new(args.value_of("file").unwrap());
+    let data = std::fs::read(path).expect("could not read file");
+    let file_name = path
+        .file_name()
+        .expect("file path has no file name")
+        .to_string_lossy();
+
+    let mut client =
+        Client::connect(args.value_of("addr").unwrap()).expect("could not connect to coordinator");
+    let id: TaskId = exit_on_error(client.call(
+        "create_task",
+        vec![
+            serde_json::to_value(profile).unwrap(),
+            file_name.into(),
+            data.into(),
+        ],
+    ));
+
+    println!("{}", id.0);
+}
+
+fn create_profile(args: &clap::ArgMatches) {
+    let json = std::fs::read_to_string(args.value_of("json-file").unwrap())
+        .expect("could not read JSON file");
+    if let Err(error) = serde_json::from_str::<serde_json::Value>(&json) {
+        eprintln!("invalid profile JSON: {}", error);
+        std::process::exit(1);
+    }
+
+    let mut client =
+        Client::connect(args.value_of("addr").unwrap()).expect("could not connect to coordinator");
+    let id: ProfileId = exit_on_error(client.call(
+        "create_profile",
+        vec![
+            args.value_of("base").unwrap().into(),
+            args.value_of("name").unwrap().into(),
+            json.into(),
+        ],
+    ));
+
+    println!("{}", id.0);
+}
+
+fn list_tasks(args: &clap::ArgMatches) {
+    let profile = args
+        .value_of("profile")
+        .map(|profile| ProfileId(profile.parse().expect("profile must be a numeric id")));
+
+    let mut client =
+        Client::connect(args.value_of("addr").unwrap()).expect("could not connect to coordinator");
+    let ids: Vec<TaskId> =
+        exit_on_error(client.call("list_tasks", vec![serde_json::to_value(profile).unwrap()]));
+
+    for id in ids {
+        println!("{}", id.0);
+    }
+}
+
+fn fetch_task(args: &clap::ArgMatches) {
+    let id = TaskId(
+        args.value_of("id")
+            .unwrap()
+            .parse()
+            .expect("id must be a numeric id"),
+    );
+
+    let mut client =
+        Client::connect(args.value_of("addr").unwrap()).expect("could not connect to coordinator");
+    let task: Task =
+        exit_on_error(client.call("fetch_task", vec![serde_json::to_value(id).unwrap()]));
+
+    if !multihash_matches(&task.data, &task.multihash) {
+        eprintln!("multihash mismatch, task data is corrupt");
+        std::process::exit(1);
+    }
+
+    std::fs::write(args.value_of("out").unwrap(), &task.data).expect("could not write output file");
+}
+
+fn serve(config_path: &str) {
+    let config: Config =
+        serde_yaml::from_reader(std::fs::File::open(config_path).expect("could not open config"))
+            .expect("could not parse config");
+
+    let postgres_uri = config
+        .validate()
+        .and_then(|()| config.postgres_uri())
+        .unwrap_or_else(|error| {
+            eprintln!("invalid config: {}", error);
+            std::process::exit(1)
+        });
+
+    // Building the pool waits until min_idle connections are open, so an unreachable database
+    // stops startup here rather than failing the first RPC
+    let application_name = match &config.instance_name {
+        Some(instance_name) => format!("{}/{}", APPLICATION_NAME, instance_name),
+        None => APPLICATION_NAME.to_string(),
+    };
+
+    let mut pool_builder = r2d2::Pool::builder()
+        .max_size(DB_POOL_MAX_SIZE)
+        .min_idle(config.db_pool_min_idle)
+        // Checking each connection as it leaves the pool replaces ones a database restart broke,
+        // instead of handing them to an RPC
+        .test_on_check_out(true);
+    if let Some(db_connection_timeout_secs) = config.db_connection_timeout_secs {
+        pool_builder =
+            pool_builder.connection_timeout(Duration::from_secs(db_connection_timeout_secs));
+    }
+    if let Some(db_max_lifetime_secs) = config.db_max_lifetime_secs {
+        pool_builder = pool_builder.max_lifetime(Some(Duration::from_secs(db_max_lifetime_secs)));
+    }
+
+    let db_pool = pool_builder
+        .connection_customizer(Box::new(ConnectionSetup {
+            application_name,
+            schema_name: config.schema_name.clone(),
+        }))
+        .build(
+            PostgresConnectionManager::new(postgres_uri, TlsMode::None)
+                .expect("could not create PostgresConnectionManager"),
+        )
+        .unwrap_or_else(|error| {
+            eprintln!("could not connect to postgres: {}", error);
+            std::process::exit(1)
+        });
+
+    // With min_idle 0 the pool opens nothing up front, so this is the first connection
+    let server_version: String = db_pool
+        .get()
+        .unwrap_or_else(|error| {
+            eprintln!("could not connect to postgres: {}", error);
+            std::process::exit(1)
+        })
+        .query("SELECT version()", &[])
+        .unwrap_or_else(|error| {
+            eprintln!("postgres did not answer a test query: {}", error);
+            std::process::exit(1)
+        })
+        .iter()
+        .next()
+        .unwrap()
+        .get(0);
+    eprintln!("connected to {}", server_version);
+
+    // A read-only coordinator may be pointed at a replica, where DDL would fail
+    if config.auto_migrate && !config.read_only {
+        migrate(&db_pool, config.schema_name.as_deref());
+    } else {
+        check_schema(&db_pool);
+    }
+
+    let rpc = RpcImpl {
+        db_pool,
+        create_rate_limiter: config
+            .create_rate_per_sec
+            .map(|create_rate_per_sec| Arc::new(RateLimiter::new(create_rate_per_sec))),
+        maintenance_mode: Arc::new(AtomicBool::new(config.maintenance_mode)),
+        import_root: config.import_root,
+        in_flight_requests: Arc::new(AtomicUsize::new(0)),
+        max_concurrent_requests: config.max_concurrent_requests,
+        read_only: config.read_only,
+        reject_duplicate_data: config.reject_duplicate_data,
+        default_base: config.default_base,
+        slow_call_threshold: config.slow_query_ms.map(Duration::from_millis),
+        default_hash: config
+            .hash_algorithm
+            .as_ref()
+            .map_or(Hash::SHA2256, |hash_algorithm| {
+                parse_hash_algorithm(hash_algorithm).unwrap()
+            }),
+    };
+
+    if let Some(msgpack_listen_port) = config.msgpack_listen_port {
+        msgpack::start(
+            rpc_handler(rpc.clone()),
+            SocketAddr::from((config.rpc_listen_address, msgpack_listen_port)),
+        )
+        .expect("msgpack server failed to start");
+    }
+
+    let runtime = config.server_threads.map(|server_threads| {
+        tokio::runtime::Builder::new()
+            .core_threads(server_threads)
+            .name_prefix("jsonrpc-eventloop-")
+            .build()
+            .expect("could not create server runtime")
+    });
+
+    let mut server_builder = jsonrpc_tcp_server::ServerBuilder::with_meta_extractor(
+        rpc_handler(rpc),
+        |context: &jsonrpc_tcp_server::RequestContext| Meta {
+            peer_addr: context.peer_addr,
+        },
+    );
+    if let Some(runtime) = &runtime {
+        server_builder = server_builder.event_loop_executor(runtime.executor());
+    }
+
+    let server = server_builder
+        .start(&SocketAddr::from((
+            config.rpc_listen_address,
+            config.rpc_listen_port,
+        )))
+        .expect("jsonrpc tcp server failed to start");
+
+    match runtime {
+        // The server only stops its own event loop, so block on the shared one instead
+        Some(runtime) => runtime.shutdown_on_idle().wait().unwrap(),
+        None => server.wait(),
+    }
+}
+
+fn migrate(db_pool: &r2d2::Pool<PostgresConnectionManager>, schema_name: Option<&str>) {
+    if let Some(schema_name) = schema_name {
+        db_pool
+            .get()
+            .unwrap()
+            .batch_execute(&format!(
+                "CREATE SCHEMA IF NOT EXISTS {}",
+                quote_identifier(schema_name)
+            ))
+            .unwrap();
+    }
+
+    db_pool
+        .get()
+        .unwrap()
+        .execute(
+            "CREATE TABLE IF NOT EXISTS profiles (
+                id BIGSERIAL PRIMARY KEY NOT NULL,
+                base VARCHAR(255) NOT NULL,
+                name VARCHAR(255) UNIQUE NOT NULL,
+                json JSONB NOT NULL
+            );",
+            &[],
+        )
+        .unwrap();
+
+    db_pool
+        .get()
+        .unwrap()
+        .execute(
+            "CREATE TABLE IF NOT EXISTS tasks (
+                id BIGSERIAL PRIMARY KEY NOT NULL,
+                profile_id BIGINT NOT NULL REFERENCES profiles (id) ON DELETE RESTRICT,
+                file_name TEXT NOT NULL,
+                data BYTEA,
+                multihash BYTEA
+            );",
+            &[],
+        )
+        .unwrap();
+
+    db_pool
+        .get()
+        .unwrap()
+        .batch_execute(
+            "ALTER TABLE profiles ADD COLUMN IF NOT EXISTS max_tasks BIGINT;
+            ALTER TABLE profiles ADD COLUMN IF NOT EXISTS version INT NOT NULL DEFAULT 1;
+            ALTER TABLE profiles ADD COLUMN IF NOT EXISTS updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
+            ALTER TABLE profiles ADD COLUMN IF NOT EXISTS archived BOOL NOT NULL DEFAULT false;
+            ALTER TABLE profiles ADD COLUMN IF NOT EXISTS hash_algorithm TEXT;",
+        )
+        .unwrap();
+
+    db_pool
+        .get()
+        .unwrap()
+        .batch_execute(
+            "ALTER TABLE tasks ADD COLUMN IF NOT EXISTS size_bytes BIGINT;
+            UPDATE tasks SET size_bytes = COALESCE(octet_length(data), 0) WHERE size_bytes IS NULL;
+            ALTER TABLE tasks ALTER COLUMN size_bytes SET NOT NULL;
+            ALTER TABLE tasks ADD COLUMN IF NOT EXISTS content_type TEXT NOT NULL DEFAULT 'application/octet-stream';
+            CREATE INDEX IF NOT EXISTS tasks_multihash_idx ON tasks (multihash);
+            ALTER TABLE tasks ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+            CREATE INDEX IF NOT EXISTS tasks_created_at_idx ON tasks (created_at);
+            CREATE INDEX IF NOT EXISTS tasks_profile_id_idx ON tasks (profile_id, id);
+            CREATE INDEX IF NOT EXISTS tasks_size_bytes_idx ON tasks (size_bytes);",
+        )
+        .unwrap();
+
+    db_pool
+        .get()
+        .unwrap()
+        .execute(
+            "CREATE TABLE IF NOT EXISTS base_defaults (
+                base VARCHAR(255) PRIMARY KEY NOT NULL,
+                json JSONB NOT NULL
+            );",
+            &[],
+        )
+        .unwrap();
+
+    db_pool
+        .get()
+        .unwrap()
+        .execute(
+            "CREATE TABLE IF NOT EXISTS task_logs (
+                task_id BIGINT NOT NULL REFERENCES tasks (id) ON DELETE CASCADE,
+                seq BIGINT NOT NULL,
+                at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                line TEXT NOT NULL,
+                PRIMARY KEY (task_id, seq)
+            );",
+            &[],
+        )
+        .unwrap();
+
+    db_pool
+        .get()
+        .unwrap()
+        .execute(
+            "CREATE TABLE IF NOT EXISTS audit_log (
+                id BIGSERIAL PRIMARY KEY NOT NULL,
+                at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                method TEXT NOT NULL,
+                args TEXT NOT NULL,
+                caller TEXT NOT NULL,
+                outcome TEXT NOT NULL
+            );",
+            &[],
+        )
+        .unwrap();
+
+    add_profile_name_index(db_pool);
+    add_task_profile_key(db_pool);
+}
+
+// Names differing only by case would make the case-insensitive index fail, so they are reported
+// for the operator to rename rather than surfacing as a raw Postgres error. Like the key below, it
+// runs last so a server started with auto_migrate off can serve rename_profile.
+fn add_profile_name_index(db_pool: &r2d2::Pool<PostgresConnectionManager>) {
+    let conn = db_pool.get().unwrap();
+
+    let has_index: bool = conn
+        .query(
+            "SELECT to_regclass('profiles_lower_name_key') IS NOT NULL",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .next()
+        .unwrap()
+        .get(0);
+    if has_index {
+        return;
+    }
+
+    let collisions: Vec<String> = conn
+        .query(
+            "SELECT string_agg(name, ', ' ORDER BY name) FROM profiles GROUP BY lower(name) HAVING COUNT(*) > 1 ORDER BY lower(name)",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    if !collisions.is_empty() {
+        eprintln!(
+            "{} groups of profile names differ only by case, so profile names can't be made unique regardless of case: {}. \
+            Start with auto_migrate off, rename all but one profile of each group with rename_profile and restart",
+            collisions.len(),
+            collisions.join("; ")
+        );
+        std::process::exit(1);
+    }
+
+    conn.batch_execute(
+        "CREATE UNIQUE INDEX IF NOT EXISTS profiles_lower_name_key ON profiles (lower(name));",
+    )
+    .unwrap();
+}
+
+// Tables created before profile_id had a foreign key declared it BIGSERIAL with no constraint.
+// The key can only be added once no task points at a missing profile, and deleting those is left
+// to the operator. This runs last, so a server started with auto_migrate off finds every table
+// and can serve delete_orphan_tasks.
+fn add_task_profile_key(db_pool: &r2d2::Pool<PostgresConnectionManager>) {
+    let conn = db_pool.get().unwrap();
+
+    let has_key: bool = conn
+        .query(
+            "SELECT EXISTS (SELECT 1 FROM pg_constraint WHERE conname = 'tasks_profile_id_fkey' AND conrelid = 'tasks'::regclass)",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .next()
+        .unwrap()
+        .get(0);
+    if has_key {
+        return;
+    }
+
+    let orphans: i64 = conn
+        .query(
+            "SELECT COUNT(*) FROM tasks t WHERE NOT EXISTS (SELECT 1 FROM profiles p WHERE p.id = t.profile_id)",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .next()
+        .unwrap()
+        .get(0);
+    if orphans > 0 {
+        eprintln!(
+            "{} tasks belong to missing profiles, so tasks.profile_id can't get its foreign key. \
+            Start with auto_migrate off, remove them with delete_orphan_tasks and restart",
+            orphans
+        );
+        std::process::exit(1);
+    }
+
+    conn.batch_execute(
+        "ALTER TABLE tasks ALTER COLUMN profile_id DROP DEFAULT;
+        DROP SEQUENCE IF EXISTS tasks_profile_id_seq;
+        ALTER TABLE tasks ADD CONSTRAINT tasks_profile_id_fkey
+            FOREIGN KEY (profile_id) REFERENCES profiles (id) ON DELETE RESTRICT;",
+    )
+    .unwrap();
+}
+
+// With auto_migrate off the schema is managed elsewhere, so only make sure it is there
+fn check_schema(db_pool: &r2d2::Pool<PostgresConnectionManager>) {
+    let conn = db_pool.get().unwrap();
+
+    let missing: Vec<&str> = TABLES
+        .iter()
+        .cloned()
+        .filter(|table| {
+            let exists: bool = conn
+                .query("SELECT to_regclass($1) IS NOT NULL", &[table])
+                .unwrap()
+                .iter()
+                .next()
+                .unwrap()
+                .get(0);
+            !exists
+        })
+        .collect();
+
+    if !missing.is_empty() {
+        eprintln!(
+            "auto_migrate is off and these tables are missing: {}",
+            missing.join(", ")
+        );
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH_NAMES: &[&str] = &[
+        "sha1",
+        "sha2-256",
+        "sha2-512",
+        "sha3-224",
+        "sha3-256",
+        "sha3-384",
+        "sha3-512",
+        "keccak-224",
+        "keccak-256",
+        "keccak-384",
+        "keccak-512",
+    ];
+
+    #[test]
+    fn parse_hash_algorithm_ignores_case() {
+        for name in HASH_NAMES {
+            let upper = name.to_uppercase();
+            let mixed: String = name
+                .chars()
+                .enumerate()
+                .map(|(i, c)| {
+                    if i % 2 == 0 {
+                        c.to_ascii_uppercase()
+                    } else {
+                        c
+                    }
+                })
+                .collect();
+
+            let hash = parse_hash_algorithm(name).unwrap();
+            assert!(hash.name().eq_ignore_ascii_case(name));
+            assert_eq!(parse_hash_algorithm(&upper), Some(hash));
+            assert_eq!(parse_hash_algorithm(&mixed), Some(hash));
+        }
+    }
+
+    #[test]
+    fn parse_hash_algorithm_rejects_unsupported_names() {
+        assert_eq!(parse_hash_algorithm("blake2b-256"), None);
+        assert_eq!(parse_hash_algorithm("blake-2b"), None);
+        assert_eq!(parse_hash_algorithm(""), None);
+    }
+
+    #[test]
+    fn multihash_matches_round_trips_every_algorithm() {
+        let data = b"violetear";
+
+        for name in HASH_NAMES {
+            let multihash = encode(parse_hash_algorithm(name).unwrap(), data).unwrap();
+
+            assert!(multihash_matches(data, &multihash), "{}", name);
+            assert!(!multihash_matches(b"violetears", &multihash), "{}", name);
+        }
+    }
+
+    #[test]
+    fn multihash_matches_rejects_malformed_multihashes() {
+        let multihash = encode(Hash::SHA2256, b"violetear").unwrap();
+
+        assert!(!multihash_matches(b"violetear", &[]));
+        assert!(!multihash_matches(
+            b"violetear",
+            &multihash[..multihash.len() - 1]
+        ));
+    }
+
+    #[test]
+    fn prefix_upper_bound_increments_the_last_byte() {
+        assert_eq!(prefix_upper_bound(&[0x12, 0x20]), Some(vec![0x12, 0x21]));
+        assert_eq!(prefix_upper_bound(&[0x00]), Some(vec![0x01]));
+    }
+
+    #[test]
+    fn prefix_upper_bound_carries_past_trailing_0xff() {
+        assert_eq!(prefix_upper_bound(&[0x12, 0xff, 0xff]), Some(vec![0x13]));
+    }
+
+    #[test]
+    fn prefix_upper_bound_has_no_bound_for_empty_or_all_0xff() {
+        assert_eq!(prefix_upper_bound(&[]), None);
+        assert_eq!(prefix_upper_bound(&[0xff, 0xff]), None);
+    }
+
+    #[test]
+    fn merge_json_merges_objects_key_by_key() {
+        let mut base = serde_json::json!({ "a": 1, "nested": { "x": 1, "y": 2 } });
+
+        merge_json(
+            &mut base,
+            serde_json::json!({ "b": 2, "nested": { "y": 3, "z": 4 } }),
+        );
+
+        assert_eq!(
+            base,
+            serde_json::json!({ "a": 1, "b": 2, "nested": { "x": 1, "y": 3, "z": 4 } })
+        );
+    }
+
+    #[test]
+    fn merge_json_replaces_other_values() {
+        let mut base = serde_json::json!({ "list": [1, 2], "value": { "x": 1 } });
+
+        merge_json(&mut base, serde_json::json!({ "list": [3], "value": null }));
+
+        assert_eq!(base, serde_json::json!({ "list": [3], "value": null }));
+    }
+
+    fn json_diff(a: serde_json::Value, b: serde_json::Value) -> serde_json::Value {
+        let mut diff = JsonDiff::default();
+        diff_json("", &a, &b, &mut diff);
+
+        serde_json::to_value(&diff).unwrap()
+    }
+
+    #[test]
+    fn diff_json_reports_nested_keys_by_dotted_path() {
+        assert_eq!(
+            json_diff(
+                serde_json::json!({ "kept": 1, "gone": 2, "nested": { "x": 1, "y": 2 } }),
+                serde_json::json!({ "kept": 1, "new": 3, "nested": { "x": 5, "z": 6 } }),
+            ),
+            serde_json::json!({
+                "added": { "new": 3, "nested.z": 6 },
+                "removed": { "gone": 2, "nested.y": 2 },
+                "changed": { "nested.x": { "from": 1, "to": 5 } },
+            })
+        );
+    }
+
+    #[test]
+    fn diff_json_compares_arrays_as_a_whole() {
+        assert_eq!(
+            json_diff(
+                serde_json::json!({ "list": [1, 2] }),
+                serde_json::json!({ "list": [1, 3] }),
+            ),
+            serde_json::json!({
+                "added": {},
+                "removed": {},
+                "changed": { "list": { "from": [1, 2], "to": [1, 3] } },
+            })
+        );
+    }
+
+    #[test]
+    fn diff_json_of_equal_values_is_empty() {
+        let value = serde_json::json!({ "a": { "b": [1] } });
+
+        assert_eq!(
+            json_diff(value.clone(), value),
+            serde_json::json!({ "added": {}, "removed": {}, "changed": {} })
+        );
+    }
 }
@@ -0,0 +1,171 @@
+use jsonrpc_core::MetaIoHandler;
+
+use crate::{Meta, RpcMiddleware};
+
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+// Each request and response is a single MessagePack value carrying the same JSON-RPC object the
+// TCP transport exchanges as a line of JSON. Clients may send byte arrays as MessagePack bin,
+// which is handed to the handler as an array of numbers. Responses send task bytes back as bin:
+// the data and multihash fields, and the whole result of the methods below.
+const BINARY_RESULT_METHODS: &[&str] = &["fetch_task_data"];
+
+const BINARY_FIELDS: &[&str] = &["data", "multihash"];
+
+pub fn start(io: MetaIoHandler<Meta, RpcMiddleware>, addr: SocketAddr) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let io = Arc::new(io);
+
+    thread::Builder::new()
+        .name("msgpack-listener".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+
+                let io = io.clone();
+                thread::spawn(move || serve_connection(&io, stream));
+            }
+        })?;
+
+    Ok(())
+}
+
+fn serve_connection(io: &MetaIoHandler<Meta, RpcMiddleware>, stream: TcpStream) {
+    let meta = match stream.peer_addr() {
+        Ok(peer_addr) => Meta { peer_addr },
+        Err(_) => return,
+    };
+    let mut reader = match stream.try_clone() {
+        Ok(read_half) => BufReader::new(read_half),
+        Err(_) => return,
+    };
+    let mut writer = BufWriter::new(stream);
+
+    while let Ok(request) = rmpv::decode::read_value(&mut reader) {
+        let request = match to_json(request) {
+            Some(request) => request,
+            None => break,
+        };
+        let binary_result = request
+            .get("method")
+            .and_then(|method| method.as_str())
+            .is_some_and(|method| BINARY_RESULT_METHODS.contains(&method));
+
+        if let Some(response) = io.handle_request_sync(&request.to_string(), meta.clone()) {
+            let response: serde_json::Value =
+                serde_json::from_str(&response).expect("handler produced invalid JSON");
+
+            if rmpv::encode::write_value(&mut writer, &response_to_msgpack(response, binary_result))
+                .is_err()
+                || writer.flush().is_err()
+            {
+                break;
+            }
+        }
+    }
+}
+
+fn to_json(value: rmpv::Value) -> Option<serde_json::Value> {
+    use rmpv::Value;
+
+    Some(match value {
+        Value::Nil => serde_json::Value::Null,
+        Value::Boolean(b) => serde_json::Value::Bool(b),
+        Value::Integer(i) => match (i.as_i64(), i.as_u64()) {
+            (Some(i), _) => i.into(),
+            (None, Some(u)) => u.into(),
+            (None, None) => return None,
+        },
+        Value::F32(f) => serde_json::Value::Number(serde_json::Number::from_f64(f64::from(f))?),
+        Value::F64(f) => serde_json::Value::Number(serde_json::Number::from_f64(f)?),
+        Value::String(s) => serde_json::Value::String(s.into_str()?),
+        Value::Binary(bytes) => bytes.into_iter().map(serde_json::Value::from).collect(),
+        Value::Array(values) => values
+            .into_iter()
+            .map(to_json)
+            .collect::<Option<Vec<_>>>()?
+            .into(),
+        Value::Map(entries) => serde_json::Value::Object(
+            entries
+                .into_iter()
+                .map(|(key, value)| Some((key.as_str()?.to_string(), to_json(value)?)))
+                .collect::<Option<_>>()?,
+        ),
+        Value::Ext(..) => return None,
+    })
+}
+
+fn response_to_msgpack(response: serde_json::Value, binary_result: bool) -> rmpv::Value {
+    match response {
+        serde_json::Value::Object(entries) => rmpv::Value::Map(
+            entries
+                .into_iter()
+                .map(|(key, value)| {
+                    let binary = binary_result && key == "result";
+                    (rmpv::Value::from(key), from_json(value, binary))
+                })
+                .collect(),
+        ),
+        // Batch responses
+        response => from_json(response, false),
+    }
+}
+
+// With binary set, an array of byte values becomes bin. Nested values are binary only under one
+// of BINARY_FIELDS.
+fn from_json(value: serde_json::Value, binary: bool) -> rmpv::Value {
+    use rmpv::Value;
+
+    match value {
+        serde_json::Value::Null => Value::Nil,
+        serde_json::Value::Bool(b) => Value::Boolean(b),
+        serde_json::Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                Value::from(i)
+            } else if let Some(u) = n.as_u64() {
+                Value::from(u)
+            } else {
+                Value::F64(n.as_f64().unwrap_or_default())
+            }
+        }
+        serde_json::Value::String(s) => Value::from(s),
+        serde_json::Value::Array(values) => {
+            if binary {
+                let bytes = values
+                    .iter()
+                    .map(|value| {
+                        value
+                            .as_u64()
+                            .filter(|&byte| byte <= 0xff)
+                            .map(|byte| byte as u8)
+                    })
+                    .collect::<Option<Vec<u8>>>();
+                if let Some(bytes) = bytes {
+                    return Value::Binary(bytes);
+                }
+            }
+
+            Value::Array(
+                values
+                    .into_iter()
+                    .map(|value| from_json(value, false))
+                    .collect(),
+            )
+        }
+        serde_json::Value::Object(entries) => Value::Map(
+            entries
+                .into_iter()
+                .map(|(key, value)| {
+                    let binary = BINARY_FIELDS.contains(&key.as_str());
+                    (Value::from(key), from_json(value, binary))
+                })
+                .collect(),
+        ),
+    }
+}
@@ -0,0 +1,52 @@
+use jsonrpc_core::futures::future::Either;
+use jsonrpc_core::futures::Future;
+use jsonrpc_core::{Call, Middleware, Output};
+
+use crate::concurrency::CallFuture;
+use crate::Meta;
+
+use std::time::{Duration, Instant};
+
+// Logs calls that take longer than threshold, with their method, to point at slow queries. Calls
+// are only timed when a threshold is set.
+pub struct SlowCallLog {
+    threshold: Option<Duration>,
+}
+
+impl SlowCallLog {
+    pub fn new(threshold: Option<Duration>) -> SlowCallLog {
+        SlowCallLog { threshold }
+    }
+}
+
+impl Middleware<Meta> for SlowCallLog {
+    type Future = jsonrpc_core::middleware::NoopFuture;
+    type CallFuture = CallFuture;
+
+    fn on_call<F, X>(&self, call: Call, meta: Meta, next: F) -> Either<Self::CallFuture, X>
+    where
+        F: Fn(Call, Meta) -> X + Send + Sync,
+        X: Future<Item = Option<Output>, Error = ()> + Send + 'static,
+    {
+        let threshold = match self.threshold {
+            Some(threshold) => threshold,
+            None => return Either::B(next(call, meta)),
+        };
+
+        let method = match &call {
+            Call::MethodCall(call) => call.method.clone(),
+            Call::Notification(notification) => notification.method.clone(),
+            Call::Invalid { .. } => return Either::B(next(call, meta)),
+        };
+        let started_at = Instant::now();
+
+        Either::A(Box::new(next(call, meta).then(move |output| {
+            let elapsed = started_at.elapsed();
+            if elapsed > threshold {
+                eprintln!("slow call: {} took {} ms", method, elapsed.as_millis());
+            }
+
+            output
+        })))
+    }
+}